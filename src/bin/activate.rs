@@ -4,23 +4,29 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
+use deploy::confirmation::{self, ConfirmationError, Request, Response};
+use deploy::lock::{AcquireLockError, LockOwner, ProfileLock};
 use deploy::logging::{LoggerType, init_logger};
+use deploy::profile::{self, ProfileError};
+use deploy::secrets::{self, SecretsError};
+use deploy::state::{ActivationState, Generation, StateError, StateFile, StateRecord};
 use signal_hook::{consts::signal::SIGHUP, iterator::Signals};
 
 use clap::Parser;
 
 use tokio::fs;
-use tokio::process::Command;
-use tokio::sync::mpsc;
-use tokio::time::timeout;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::process::{Child, Command};
+use tokio::time::{Instant, timeout, timeout_at};
 
+use std::process::ExitStatus;
 use std::time::Duration;
 
 use std::env;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 
-use notify::{RecommendedWatcher, RecursiveMode, Watcher, recommended_watcher};
-
 use thiserror::Error;
 
 use log::{debug, error, info, warn};
@@ -48,6 +54,13 @@ enum SubCommand {
     Activate(ActivateOpts),
     Wait(WaitOpts),
     Revoke(RevokeOpts),
+    StageSecrets(StageSecretsOpts),
+    Status(StatusOpts),
+    Confirm(ConfirmOpts),
+    Reject(RejectOpts),
+    Extend(ExtendOpts),
+    State(StateOpts),
+    Recover(RecoverOpts),
 }
 
 /// Activate a profile
@@ -75,6 +88,11 @@ struct ActivateOpts {
     #[arg(long)]
     confirm_timeout: u16,
 
+    /// Maximum time the deployer may extend the wait for confirmation to with heartbeats, counted
+    /// from when the wait started
+    #[arg(long)]
+    confirm_timeout_max: Option<u16>,
+
     /// Wait for confirmation after deployment and rollback if not confirmed
     #[arg(long)]
     magic_rollback: bool,
@@ -98,6 +116,36 @@ struct ActivateOpts {
     /// Path for any temporary files that may be needed during activation
     #[arg(long)]
     temp_path: PathBuf,
+
+    /// Kill the activation script and roll back if it runs for longer than this
+    #[arg(long)]
+    script_timeout: Option<u16>,
+
+    /// Also wait for confirmation after the node rebooted into the new generation, with
+    /// `activate-rs recover` at boot, rolling back and rebooting again if it doesn't come
+    #[arg(long, requires = "magic_rollback")]
+    boot_check: bool,
+
+    /// Install the secrets staged with `stage-secrets` before running the activation script
+    #[arg(long)]
+    secrets: bool,
+
+    /// User running the deployment, recorded in the profile lock
+    #[arg(long)]
+    deployer_user: Option<String>,
+    /// Machine the deployment runs on, recorded in the profile lock
+    #[arg(long)]
+    deployer_host: Option<String>,
+    /// Run id of the deployment, recorded in the profile lock
+    #[arg(long)]
+    run_id: Option<String>,
+    /// Take the profile lock even if another deployment holds it
+    #[arg(long)]
+    force_unlock: bool,
+
+    /// Manage profile generations natively rather than with `nix-env`
+    #[arg(long)]
+    native_profiles: bool,
 }
 
 /// Wait for profile activation
@@ -115,6 +163,17 @@ struct WaitOpts {
     activation_timeout: Option<u16>,
 }
 
+/// Store secrets read from stdin until the profile is activated
+#[derive(Parser, Debug)]
+struct StageSecretsOpts {
+    /// The closure the secrets belong to
+    closure: String,
+
+    /// Path for any temporary files that may be needed during activation
+    #[arg(long)]
+    temp_path: PathBuf,
+}
+
 /// Revoke profile activation
 #[derive(Parser, Debug)]
 struct RevokeOpts {
@@ -127,6 +186,118 @@ struct RevokeOpts {
     /// The profile name
     #[arg(long, requires = "profile_user")]
     profile_name: Option<String>,
+
+    /// Manage profile generations natively rather than with `nix-env`
+    #[arg(long)]
+    native_profiles: bool,
+}
+
+/// Confirm an activation, given its token on stdin
+#[derive(Parser, Debug)]
+struct ConfirmOpts {
+    /// The closure whose activation to confirm
+    closure: String,
+
+    /// Path for any temporary files that may be needed during activation
+    #[arg(long)]
+    temp_path: PathBuf,
+}
+
+/// Roll back an activation that is waiting for confirmation right away, given its token on stdin
+#[derive(Parser, Debug)]
+struct RejectOpts {
+    /// The closure whose activation to reject
+    closure: String,
+
+    /// Path for any temporary files that may be needed during activation
+    #[arg(long)]
+    temp_path: PathBuf,
+}
+
+/// Keep an activation waiting for confirmation for longer, given its token on stdin
+#[derive(Parser, Debug)]
+struct ExtendOpts {
+    /// The closure whose activation to keep waiting
+    closure: String,
+
+    /// Path for any temporary files that may be needed during activation
+    #[arg(long)]
+    temp_path: PathBuf,
+
+    /// How long to wait for confirmation from now on
+    #[arg(long)]
+    seconds: u16,
+}
+
+/// Check that a closure is the active and confirmed generation of a profile
+#[derive(Parser, Debug)]
+#[command(group(
+    clap::ArgGroup::new("profile")
+        .required(true)
+        .multiple(false)
+        .args(&["profile_path","profile_user"])
+))]
+struct StatusOpts {
+    /// The closure expected to be active
+    closure: String,
+    /// The profile path to check
+    #[arg(long)]
+    profile_path: Option<String>,
+    /// The profile user if explicit profile path is not specified
+    #[arg(long, requires = "profile_name")]
+    profile_user: Option<String>,
+    /// The profile name
+    #[arg(long, requires = "profile_user")]
+    profile_name: Option<String>,
+
+    /// Path for any temporary files that may be needed during activation
+    #[arg(long)]
+    temp_path: PathBuf,
+}
+
+/// Print the recorded activation state of a profile as JSON
+#[derive(Parser, Debug)]
+#[command(group(
+    clap::ArgGroup::new("profile")
+        .required(true)
+        .multiple(false)
+        .args(&["profile_path","profile_user"])
+))]
+struct StateOpts {
+    /// The profile path to query
+    #[arg(long)]
+    profile_path: Option<String>,
+    /// The profile user if explicit profile path is not specified
+    #[arg(long, requires = "profile_name")]
+    profile_user: Option<String>,
+    /// The profile name
+    #[arg(long, requires = "profile_user")]
+    profile_name: Option<String>,
+}
+
+/// Roll back profiles whose activation was left waiting for confirmation, e.g. by a reboot, and
+/// run armed boot-time checks
+#[derive(Parser, Debug)]
+#[command(group(
+    clap::ArgGroup::new("profile")
+        .multiple(false)
+        .args(&["profile_path","profile_user"])
+))]
+struct RecoverOpts {
+    /// The profile path to recover, all profiles under the Nix state directory if none is given
+    #[arg(long)]
+    profile_path: Option<String>,
+    /// The profile user if explicit profile path is not specified
+    #[arg(long, requires = "profile_name")]
+    profile_user: Option<String>,
+    /// The profile name
+    #[arg(long, requires = "profile_user")]
+    profile_name: Option<String>,
+
+    /// Manage profile generations natively rather than with `nix-env`, for activations recorded
+    /// by versions that didn't store how they set the profile
+    #[arg(long)]
+    native_profiles: bool,
 }
 
 #[derive(Error, Debug)]
@@ -149,10 +320,170 @@ pub enum DeactivateError {
     Reactivate(std::io::Error),
     #[error("Command for re-activating the last generation resulted in a bad exit code: {0:?}")]
     ReactivateExit(Option<i32>),
+    #[error("Failed to restore previous secrets: {0}")]
+    RestoreSecrets(SecretsError),
+    #[error("Generation {0} to roll back to no longer points to {1}")]
+    GenerationGone(u64, String),
+    #[error("{0}")]
+    Profile(#[from] ProfileError),
 }
 
-pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
-    warn!("De-activating due to error");
+#[derive(Error, Debug)]
+pub enum GenerationError {
+    #[error("Failed to run command for listing generations: {0}")]
+    List(std::io::Error),
+    #[error("Command for listing generations resulted in a bad exit code: {0:?}")]
+    ListExit(Option<i32>),
+    #[error("Error converting generation list output to utf8: {0}")]
+    DecodeListUtf8(std::string::FromUtf8Error),
+    #[error("Failed to resolve profile {0}: {1}")]
+    Resolve(String, std::io::Error),
+    #[error("{0}")]
+    Profile(#[from] ProfileError),
+}
+
+/// The generation the profile points to, if it exists yet
+async fn current_generation(
+    profile_path: &str,
+    native_profiles: bool,
+) -> Result<Option<Generation>, GenerationError> {
+    if native_profiles {
+        return Ok(profile::current_generation(profile_path)?);
+    }
+
+    if fs::symlink_metadata(profile_path).await.is_err() {
+        return Ok(None);
+    }
+
+    let generations_list = nix_env_list_generations(profile_path).await?;
+    let Some(number) = deploy::state::parse_current_generation(&generations_list) else {
+        return Ok(None);
+    };
+    let path = fs::canonicalize(profile_path)
+        .await
+        .map_err(|e| GenerationError::Resolve(profile_path.to_string(), e))?;
+
+    Ok(Some(Generation {
+        number,
+        path: path.display().to_string(),
+    }))
+}
+
+/// The number of the newest generation of the profile, which `--set` reuses if it points to the
+/// closure already
+async fn latest_generation(
+    profile_path: &str,
+    native_profiles: bool,
+) -> Result<Option<u64>, GenerationError> {
+    if native_profiles {
+        return Ok(profile::list_generations(profile_path)?
+            .last()
+            .map(|x| x.number));
+    }
+
+    if fs::symlink_metadata(profile_path).await.is_err() {
+        return Ok(None);
+    }
+
+    let generations_list = nix_env_list_generations(profile_path).await?;
+    Ok(deploy::state::parse_latest_generation(&generations_list))
+}
+
+async fn nix_env_list_generations(profile_path: &str) -> Result<String, GenerationError> {
+    let nix_env_list_generations_out = Command::new("nix-env")
+        .arg("-p")
+        .arg(profile_path)
+        .arg("--list-generations")
+        .output()
+        .await
+        .map_err(GenerationError::List)?;
+
+    match nix_env_list_generations_out.status.code() {
+        Some(0) => (),
+        a => return Err(GenerationError::ListExit(a)),
+    };
+
+    String::from_utf8(nix_env_list_generations_out.stdout).map_err(GenerationError::DecodeListUtf8)
+}
+
+/// Switches the profile back to exactly `previous` and deletes `created`, leaving generations that
+/// others created meanwhile alone
+async fn roll_back_to(
+    profile_path: &str,
+    previous: &Generation,
+    created: Option<u64>,
+    native_profiles: bool,
+) -> Result<(), DeactivateError> {
+    // a generation number is only reused after the generation was deleted
+    let previous_link = format!("{}-{}-link", profile_path, previous.number);
+    match fs::canonicalize(&previous_link).await {
+        Ok(x) if x == Path::new(&previous.path) => (),
+        _ => {
+            return Err(DeactivateError::GenerationGone(
+                previous.number,
+                previous.path.clone(),
+            ));
+        }
+    }
+
+    warn!("Switching back to generation {}", previous.number);
+
+    if native_profiles {
+        profile::switch_generation(profile_path, previous.number)?;
+    } else {
+        let nix_env_switch_generation_exit_status = Command::new("nix-env")
+            .arg("-p")
+            .arg(profile_path)
+            .arg("--switch-generation")
+            .arg(previous.number.to_string())
+            .status()
+            .await
+            .map_err(DeactivateError::Rollback)?;
+
+        match nix_env_switch_generation_exit_status.code() {
+            Some(0) => (),
+            a => return Err(DeactivateError::RollbackExit(a)),
+        };
+    }
+
+    // setting the profile to the closure it already pointed to doesn't create a generation
+    if let Some(created) = created.filter(|x| *x != previous.number) {
+        warn!("Removing generation by ID {}", created);
+
+        if native_profiles {
+            profile::delete_generation(profile_path, created)?;
+            return Ok(());
+        }
+
+        let nix_env_delete_generation_exit_status = Command::new("nix-env")
+            .arg("-p")
+            .arg(profile_path)
+            .arg("--delete-generations")
+            .arg(created.to_string())
+            .status()
+            .await
+            .map_err(DeactivateError::DeleteGen)?;
+
+        match nix_env_delete_generation_exit_status.code() {
+            Some(0) => (),
+            a => return Err(DeactivateError::DeleteGenExit(a)),
+        };
+    }
+
+    Ok(())
+}
+
+/// Rolls back to whatever generation precedes the current one and deletes the latest generation,
+/// for activations that didn't record their previous generation
+async fn roll_back_last(profile_path: &str, native_profiles: bool) -> Result<(), DeactivateError> {
+    if native_profiles {
+        profile::roll_back(profile_path)?;
+        if let Some(last) = profile::list_generations(profile_path)?.last() {
+            warn!("Removing generation by ID {}", last.number);
+            profile::delete_generation(profile_path, last.number)?;
+        }
+        return Ok(());
+    }
 
     let nix_env_rollback_exit_status = Command::new("nix-env")
         .arg("-p")
@@ -212,10 +543,40 @@ pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
         a => return Err(DeactivateError::DeleteGenExit(a)),
     };
 
+    Ok(())
+}
+
+/// Rolls back to the generation `record` says the activation started from, or to the previous one
+/// without a record, re-activating it for the next boot only if `boot`
+pub async fn deactivate(
+    profile_path: &str,
+    boot: bool,
+    record: Option<&StateRecord>,
+    native_profiles: bool,
+) -> Result<(), DeactivateError> {
+    warn!("De-activating due to error");
+
+    // the broken generation is worse than a missing secret, so the rollback goes ahead regardless
+    let restored = secrets::restore_secrets(profile_path);
+    if let Err(e) = &restored {
+        error!(
+            "Failed to restore previous secrets, rolling back anyway: {}",
+            e
+        );
+    }
+
+    match record.and_then(|x| Some((x.previous_generation.as_ref()?, x.generation))) {
+        Some((previous, created)) => {
+            roll_back_to(profile_path, previous, created, native_profiles).await?
+        }
+        None => roll_back_last(profile_path, native_profiles).await?,
+    }
+
     info!("Attempting to re-activate the last generation");
 
     let re_activate_exit_status = Command::new(format!("{}/deploy-rs-activate", profile_path))
         .env("PROFILE", profile_path)
+        .env("BOOT", if boot { "1" } else { "0" })
         .current_dir(profile_path)
         .status()
         .await
@@ -226,150 +587,215 @@ pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
         a => return Err(DeactivateError::ReactivateExit(a)),
     };
 
-    Ok(())
+    restored.map_err(DeactivateError::RestoreSecrets)
 }
 
 #[derive(Error, Debug)]
 pub enum ActivationConfirmationError {
     #[error("Failed to create activation confirmation directory: {0}")]
     CreateConfirmDir(std::io::Error),
-    #[error("Failed to create activation confirmation file: {0}")]
-    CreateConfirmFile(std::io::Error),
-    #[error("Could not watch for activation sentinel: {0}")]
-    Watcher(#[from] notify::Error),
-    #[error("Error waiting for confirmation event: {0}")]
-    WaitingError(#[from] DangerZoneError),
-}
-
-#[derive(Error, Debug)]
-pub enum DangerZoneError {
+    #[error("{0}")]
+    Confirmation(#[from] ConfirmationError),
+    #[error("Failed to listen for activation confirmation: {0}")]
+    Listen(std::io::Error),
     #[error("Timeout elapsed for confirmation")]
     TimesUp,
-    #[error("inotify stream ended without activation confirmation")]
-    NoConfirmation,
-    #[error("inotify encountered an error: {0}")]
-    Watch(notify::Error),
+    #[error("The activation was rejected by the deployer")]
+    Rejected,
 }
 
-async fn danger_zone(
-    mut events: mpsc::Receiver<Result<(), notify::Error>>,
-    confirm_timeout: u16,
-) -> Result<(), DangerZoneError> {
-    info!("Waiting for confirmation event...");
+/// How long a connection to the confirmation socket may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reads a request from the deployer and answers it, returning its command if the nonce matches
+async fn handle_request(stream: UnixStream, nonce: &str) -> Option<confirmation::Command> {
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+
+    let command = match timeout(REQUEST_TIMEOUT, BufReader::new(read).read_line(&mut line)).await {
+        // `wait` only checks that we are listening
+        Ok(Ok(0)) => return None,
+        Ok(Ok(_)) => match serde_json::from_str::<Request>(&line) {
+            Ok(request) if confirmation::tokens_match(nonce, &request.nonce) => Ok(request.command),
+            Ok(_) => Err("the confirmation token doesn't match".to_string()),
+            Err(e) => Err(format!("invalid request: {}", e)),
+        },
+        Ok(Err(e)) => {
+            debug!("Failed to read a confirmation request: {}", e);
+            return None;
+        }
+        Err(_) => {
+            debug!("Timed out reading a confirmation request");
+            return None;
+        }
+    };
 
-    match timeout(Duration::from_secs(confirm_timeout as u64), events.recv()).await {
-        Ok(Some(Ok(()))) => Ok(()),
-        Ok(Some(Err(e))) => Err(DangerZoneError::Watch(e)),
-        Ok(None) => Err(DangerZoneError::NoConfirmation),
-        Err(_) => Err(DangerZoneError::TimesUp),
+    let response = match &command {
+        Ok(_) => Response::Ok,
+        Err(e) => {
+            warn!("Refused a confirmation request: {}", e);
+            Response::Error(e.clone())
+        }
+    };
+    let mut response = serde_json::to_vec(&response).expect("responses can be serialized");
+    response.push(b'\n');
+    if let Err(e) = write.write_all(&response).await {
+        debug!("Failed to answer a confirmation request: {}", e);
     }
+
+    command.ok()
 }
 
 pub async fn activation_confirmation(
     temp_path: PathBuf,
     confirm_timeout: u16,
+    confirm_timeout_max: Option<u16>,
     closure: String,
+    nonce: String,
 ) -> Result<(), ActivationConfirmationError> {
-    let lock_path = deploy::make_lock_path(&temp_path, &closure);
+    debug!("Ensuring parent directory exists for confirmation socket");
+
+    fs::create_dir_all(&temp_path)
+        .await
+        .map_err(ActivationConfirmationError::CreateConfirmDir)?;
 
-    debug!("Ensuring parent directory exists for canary file");
+    debug!("Creating confirmation socket");
 
-    if let Some(parent) = lock_path.parent() {
-        fs::create_dir_all(parent)
-            .await
-            .map_err(ActivationConfirmationError::CreateConfirmDir)?;
+    let socket_path = confirmation::create_confirmation_dir(&temp_path, &closure)?;
+    let listener = UnixListener::bind(&socket_path).map_err(ActivationConfirmationError::Listen)?;
+
+    info!("Waiting for confirmation event...");
+
+    let started = Instant::now();
+    let mut deadline = started + Duration::from_secs(confirm_timeout as u64);
+    // heartbeats can't keep a broken activation around forever
+    let latest =
+        started + Duration::from_secs(confirm_timeout.max(confirm_timeout_max.unwrap_or(0)) as u64);
+
+    let confirmation = loop {
+        let stream = match timeout_at(deadline, listener.accept()).await {
+            Ok(Ok((stream, _))) => stream,
+            Ok(Err(e)) => break Err(ActivationConfirmationError::Listen(e)),
+            Err(_) => break Err(ActivationConfirmationError::TimesUp),
+        };
+
+        match handle_request(stream, &nonce).await {
+            Some(confirmation::Command::Confirm) => break Ok(()),
+            Some(confirmation::Command::Rollback) => {
+                break Err(ActivationConfirmationError::Rejected);
+            }
+            Some(confirmation::Command::Extend { seconds }) => {
+                deadline = (Instant::now() + Duration::from_secs(seconds as u64)).min(latest);
+                info!(
+                    "Waiting for confirmation for another {}s",
+                    deadline.saturating_duration_since(Instant::now()).as_secs()
+                );
+            }
+            None => (),
+        }
+    };
+
+    drop(listener);
+    // every activation creates a directory of its own, which would otherwise pile up in `tempPath`
+    if let Some(confirmation_dir) = socket_path.parent() {
+        match fs::remove_dir_all(confirmation_dir).await {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => warn!("Failed to remove the confirmation directory: {}", e),
+        }
     }
 
-    debug!("Creating canary file");
+    confirmation
+}
 
-    fs::File::create(&lock_path)
-        .await
-        .map_err(ActivationConfirmationError::CreateConfirmFile)?;
+/// Reads the confirmation token the deployer sends on stdin, after the sudo password if any
+fn read_token() -> std::io::Result<String> {
+    let mut token = String::new();
+    std::io::stdin().read_line(&mut token)?;
+
+    match token.trim() {
+        "" => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "no confirmation token was given on stdin",
+        )),
+        token => Ok(token.to_string()),
+    }
+}
 
-    debug!("Creating notify watcher");
+#[derive(Error, Debug)]
+pub enum ConfirmError {
+    #[error("Failed to read the confirmation token: {0}")]
+    ReadToken(std::io::Error),
+    #[error("{0}")]
+    Confirmation(#[from] ConfirmationError),
+}
 
-    let (deleted, done) = mpsc::channel(1);
+/// Sends `command` to the activation of `closure`, along with the token read from stdin
+async fn send_command(
+    temp_path: &Path,
+    closure: &str,
+    command: confirmation::Command,
+) -> Result<(), ConfirmError> {
+    let nonce = read_token().map_err(ConfirmError::ReadToken)?;
+    confirmation::send_request(temp_path, closure, &Request { nonce, command }).await?;
+    Ok(())
+}
 
-    let mut watcher: RecommendedWatcher =
-        recommended_watcher(move |res: Result<notify::event::Event, notify::Error>| {
-            let send_result = match res {
-                Ok(e) if e.kind == notify::EventKind::Remove(notify::event::RemoveKind::File) => {
-                    debug!("Got worthy removal event, sending on channel");
-                    deleted.try_send(Ok(()))
-                }
-                Err(e) => {
-                    debug!("Got error waiting for removal event, sending on channel");
-                    deleted.try_send(Err(e))
-                }
-                Ok(_) => Ok(()), // ignore non-removal events
-            };
+async fn confirm(temp_path: PathBuf, closure: String) -> Result<(), ConfirmError> {
+    send_command(&temp_path, &closure, confirmation::Command::Confirm).await?;
 
-            if let Err(e) = send_result {
-                error!("Could not send file system event to watcher: {}", e);
-            }
-        })?;
+    info!("Confirmed the activation of {}", closure);
 
-    watcher.watch(&lock_path, RecursiveMode::NonRecursive)?;
+    Ok(())
+}
 
-    danger_zone(done, confirm_timeout)
-        .await
-        .map_err(ActivationConfirmationError::WaitingError)
+async fn extend(temp_path: PathBuf, closure: String, seconds: u16) -> Result<(), ConfirmError> {
+    send_command(
+        &temp_path,
+        &closure,
+        confirmation::Command::Extend { seconds },
+    )
+    .await?;
+
+    debug!("Extended the confirmation window of {}", closure);
+
+    Ok(())
+}
+
+async fn reject(temp_path: PathBuf, closure: String) -> Result<(), ConfirmError> {
+    send_command(&temp_path, &closure, confirmation::Command::Rollback).await?;
+
+    info!("Rejected the activation of {}", closure);
+
+    Ok(())
 }
 
+/// How often `wait` checks whether the activation listens for confirmation yet
+const WAIT_INTERVAL: Duration = Duration::from_millis(250);
+
 #[derive(Error, Debug)]
 pub enum WaitError {
-    #[error("Error creating watcher for activation: {0}")]
-    Watcher(#[from] notify::Error),
-    #[error("Error waiting for activation: {0}")]
-    Waiting(#[from] DangerZoneError),
+    #[error("Timeout elapsed waiting for the activation")]
+    TimesUp,
 }
 pub async fn wait(
     temp_path: PathBuf,
     closure: String,
     activation_timeout: Option<u16>,
 ) -> Result<(), WaitError> {
-    let lock_path = deploy::make_lock_path(&temp_path, &closure);
-
-    let (created, done) = mpsc::channel(1);
-
-    let mut watcher: RecommendedWatcher = {
-        // TODO: fix wasteful clone
-        let lock_path = lock_path.clone();
-
-        recommended_watcher(move |res: Result<notify::event::Event, notify::Error>| {
-            let send_result = match res {
-                Ok(e) if e.kind == notify::EventKind::Create(notify::event::CreateKind::File) => {
-                    match &e.paths[..] {
-                        [x] => match lock_path.canonicalize() {
-                            // 'lock_path' may not exist yet when some other files are created in 'temp_path'
-                            // x is already supposed to be canonical path
-                            Ok(lock_path) if x == &lock_path => created.try_send(Ok(())),
-                            _ => Ok(()),
-                        },
-                        _ => Ok(()),
-                    }
-                }
-                Err(e) => created.try_send(Err(e)),
-                Ok(_) => Ok(()), // ignore non-removal events
-            };
+    let deadline = Instant::now() + Duration::from_secs(activation_timeout.unwrap_or(240) as u64);
 
-            if let Err(e) = send_result {
-                error!("Could not send file system event to watcher: {}", e);
-            }
-        })?
-    };
+    info!("Waiting for the activation to listen for confirmation...");
 
-    watcher.watch(&temp_path, RecursiveMode::NonRecursive)?;
-
-    // Avoid a potential race condition by checking for existence after watcher creation
-    if fs::metadata(&lock_path).await.is_ok() {
-        watcher.unwatch(&temp_path)?;
-        return Ok(());
+    // only a socket in a directory that only we have access to counts, anyone could create others
+    while !confirmation::is_waiting(&temp_path, &closure).await {
+        if Instant::now() >= deadline {
+            return Err(WaitError::TimesUp);
+        }
+        tokio::time::sleep(WAIT_INTERVAL).await;
     }
 
-    danger_zone(done, activation_timeout.unwrap_or(240)).await?;
-
-    info!("Found canary file, done waiting!");
+    info!("Found confirmation socket, done waiting!");
 
     Ok(())
 }
@@ -380,49 +806,266 @@ pub enum ActivateError {
     SetProfile(std::io::Error),
     #[error("The command for setting profile resulted in a bad exit code: {0:?}")]
     SetProfileExit(Option<i32>),
+    #[error("Failed to set profile: {0}")]
+    Profile(ProfileError),
+
+    #[error("Failed to install secrets: {0}")]
+    Secrets(SecretsError),
+
+    #[error("Failed to read the confirmation token: {0}")]
+    ReadToken(std::io::Error),
+
+    #[error("Failed to find the current generation of the profile: {0}")]
+    Generation(#[from] GenerationError),
 
     #[error("Failed to execute the activation script: {0}")]
     RunActivate(std::io::Error),
     #[error("The activation script resulted in a bad exit code: {0:?}")]
     RunActivateExit(Option<i32>),
+    #[error("The activation script didn't finish within {0}s and was killed")]
+    ScriptTimeout(u16),
 
     #[error("There was an error de-activating after an error was encountered: {0}")]
     Deactivate(#[from] DeactivateError),
 
     #[error("Failed to get activation confirmation: {0}")]
     ActivationConfirmation(#[from] ActivationConfirmationError),
+
+    #[error("{0}")]
+    Lock(#[from] AcquireLockError),
+
+    #[error("Failed to arm the boot-time check: {0}")]
+    ArmBootCheck(StateError),
 }
 
+/// How long a timed out activation script gets to stop before it is killed outright
+const SCRIPT_KILL_GRACE: Duration = Duration::from_secs(10);
+
+/// Stops everything in the process group led by `child`
+async fn kill_process_group(child: &mut Child) {
+    // already reaped
+    let Some(pgid) = child.id().map(|x| x as libc::pid_t) else {
+        return;
+    };
+
+    // SAFETY: kill has no memory safety requirements
+    unsafe { libc::kill(-pgid, libc::SIGTERM) };
+    if timeout(SCRIPT_KILL_GRACE, child.wait()).await.is_err() {
+        warn!("The activation script didn't stop, killing it");
+    }
+    // also reaches whatever it started and left behind
+    unsafe { libc::kill(-pgid, libc::SIGKILL) };
+    if let Err(e) = child.wait().await {
+        warn!("Failed to wait for the killed activation script: {}", e);
+    }
+}
+
+async fn run_activation_script(
+    activation_location: &str,
+    dry_activate: bool,
+    boot: bool,
+    test: bool,
+    script_timeout: Option<u16>,
+) -> Result<ExitStatus, ActivateError> {
+    let mut child = Command::new(format!("{}/deploy-rs-activate", activation_location))
+        .env("PROFILE", activation_location)
+        .env("DRY_ACTIVATE", if dry_activate { "1" } else { "0" })
+        .env("BOOT", if boot { "1" } else { "0" })
+        .env("TEST", if test { "1" } else { "0" })
+        .current_dir(activation_location)
+        // its own process group, so that a hung script can be stopped along with everything it started
+        .process_group(0)
+        .spawn()
+        .map_err(ActivateError::RunActivate)?;
+
+    let Some(script_timeout) = script_timeout else {
+        return child.wait().await.map_err(ActivateError::RunActivate);
+    };
+
+    match timeout(Duration::from_secs(script_timeout as u64), child.wait()).await {
+        Ok(status) => status.map_err(ActivateError::RunActivate),
+        Err(_) => {
+            error!(
+                "The activation script didn't finish within {}s, stopping it",
+                script_timeout
+            );
+            kill_process_group(&mut child).await;
+            Err(ActivateError::ScriptTimeout(script_timeout))
+        }
+    }
+}
+
+/// Persists that the activation reached `to`, without failing the activation if that doesn't work
+fn record_state(state: &mut Option<StateFile>, to: ActivationState) {
+    if let Some(state) = state
+        && let Err(e) = state.transition(to)
+    {
+        warn!("{}", e);
+    }
+}
+
+/// Rolls back a failed activation if `roll_back`, recording the outcome either way
+async fn handle_failure(
+    profile_path: &str,
+    state: &mut Option<StateFile>,
+    roll_back: bool,
+    boot: bool,
+    native_profiles: bool,
+) -> Result<(), DeactivateError> {
+    // nothing is left to check after a reboot
+    if let Some(state) = state
+        && let Err(e) = state.disarm_boot_check()
+    {
+        warn!("{}", e);
+    }
+
+    if roll_back {
+        let record = state.as_ref().map(|x| x.record().clone());
+        deactivate(profile_path, boot, record.as_ref(), native_profiles).await?;
+        record_state(state, ActivationState::RolledBack);
+    } else {
+        record_state(state, ActivationState::Failed);
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn activate(
     profile_path: String,
     closure: String,
     auto_rollback: bool,
     temp_path: PathBuf,
     confirm_timeout: u16,
+    confirm_timeout_max: Option<u16>,
     magic_rollback: bool,
     dry_activate: bool,
     boot: bool,
     test: bool,
+    script_timeout: Option<u16>,
+    boot_check: bool,
+    secrets: bool,
+    lock_owner: LockOwner,
+    force_unlock: bool,
+    native_profiles: bool,
 ) -> Result<(), ActivateError> {
+    // read before anything changes, so that a deployer not sending one fails early
+    let confirm_token = if magic_rollback && (!boot || boot_check) && !dry_activate {
+        Some(read_token().map_err(ActivateError::ReadToken)?)
+    } else {
+        None
+    };
+
+    // held until the activation is confirmed or rolled back, so that deployments don't interleave
+    let _lock = if dry_activate {
+        None
+    } else {
+        Some(ProfileLock::acquire(
+            &profile_path,
+            &lock_owner,
+            force_unlock,
+        )?)
+    };
+
+    // persisted, so that it is known how far the activation got even if activate-rs dies
+    let mut state = (!dry_activate).then(|| {
+        StateFile::new(
+            &profile_path,
+            &closure,
+            &temp_path,
+            lock_owner.run_id.clone(),
+            native_profiles,
+        )
+    });
+
+    if boot_check && let (Some(state), Some(token)) = (&mut state, &confirm_token) {
+        state
+            .arm_boot_check(token, confirm_timeout)
+            .map_err(ActivateError::ArmBootCheck)?;
+    }
+
     if !dry_activate {
+        // recorded, so that a rollback returns to exactly this generation
+        let previous_generation = current_generation(&profile_path, native_profiles).await?;
+        let latest_generation = latest_generation(&profile_path, native_profiles).await?;
+        if let Some(state) = &mut state {
+            state.set_previous_generation(previous_generation);
+        }
+
         info!("Activating profile");
-        let nix_env_set_exit_status = Command::new("nix-env")
-            .arg("-p")
-            .arg(&profile_path)
-            .arg("--set")
-            .arg(&closure)
-            .status()
-            .await
-            .map_err(ActivateError::SetProfile)?;
-        match nix_env_set_exit_status.code() {
-            Some(0) => (),
-            a => {
-                if auto_rollback && !dry_activate {
-                    deactivate(&profile_path).await?;
+        if native_profiles {
+            let nix_state_dir = env::var("NIX_STATE_DIR").unwrap_or("/nix/var/nix".to_string());
+            if let Err(e) = profile::set_profile(&profile_path, &closure, Path::new(&nix_state_dir))
+            {
+                handle_failure(
+                    &profile_path,
+                    &mut state,
+                    auto_rollback,
+                    boot,
+                    native_profiles,
+                )
+                .await?;
+                return Err(ActivateError::Profile(e));
+            }
+        } else {
+            let nix_env_set_exit_status = Command::new("nix-env")
+                .arg("-p")
+                .arg(&profile_path)
+                .arg("--set")
+                .arg(&closure)
+                .status()
+                .await
+                .map_err(ActivateError::SetProfile)?;
+            match nix_env_set_exit_status.code() {
+                Some(0) => (),
+                a => {
+                    handle_failure(
+                        &profile_path,
+                        &mut state,
+                        auto_rollback,
+                        boot,
+                        native_profiles,
+                    )
+                    .await?;
+                    return Err(ActivateError::SetProfileExit(a));
                 }
-                return Err(ActivateError::SetProfileExit(a));
+            };
+        }
+        match current_generation(&profile_path, native_profiles).await {
+            Ok(x) => {
+                // an existing generation is reused when it points to the closure already, e.g.
+                // after rolling back from it, and isn't ours to delete on rollback
+                let created = x
+                    .map(|x| x.number)
+                    .filter(|x| latest_generation.is_none_or(|latest| *x > latest));
+                if let Some(state) = &mut state {
+                    state.set_generation(created);
+                }
+            }
+            Err(e) => warn!("{}", e),
+        }
+        record_state(&mut state, ActivationState::SetProfile);
+
+        let secrets_result = if secrets {
+            info!("Installing secrets");
+            match secrets::read_staged_secrets(&secrets::make_secrets_path(&temp_path, &closure)) {
+                Ok(staged) => secrets::install_secrets(&profile_path, &staged).await,
+                Err(e) => Err(e),
             }
+        } else {
+            secrets::discard_backups(&profile_path)
         };
+
+        if let Err(e) = secrets_result {
+            handle_failure(
+                &profile_path,
+                &mut state,
+                auto_rollback,
+                boot,
+                native_profiles,
+            )
+            .await?;
+            return Err(ActivateError::Secrets(e));
+        }
     }
 
     debug!("Running activation script");
@@ -433,21 +1076,31 @@ pub async fn activate(
         &profile_path
     };
 
-    let activate_status = match Command::new(format!("{}/deploy-rs-activate", activation_location))
-        .env("PROFILE", activation_location)
-        .env("DRY_ACTIVATE", if dry_activate { "1" } else { "0" })
-        .env("BOOT", if boot { "1" } else { "0" })
-        .env("TEST", if test { "1" } else { "0" })
-        .current_dir(activation_location)
-        .status()
-        .await
-        .map_err(ActivateError::RunActivate)
+    let activate_status = match run_activation_script(
+        activation_location,
+        dry_activate,
+        boot,
+        test,
+        script_timeout,
+    )
+    .await
     {
         Ok(x) => x,
+        // a half-finished activation is rolled back regardless of `auto_rollback`, as nothing else
+        // would bring the node back to a known state
+        Err(e @ ActivateError::ScriptTimeout(_)) if !dry_activate => {
+            handle_failure(&profile_path, &mut state, true, boot, native_profiles).await?;
+            return Err(e);
+        }
         Err(e) => {
-            if auto_rollback && !dry_activate {
-                deactivate(&profile_path).await?;
-            }
+            handle_failure(
+                &profile_path,
+                &mut state,
+                auto_rollback && !dry_activate,
+                boot,
+                native_profiles,
+            )
+            .await?;
             return Err(e);
         }
     };
@@ -456,9 +1109,14 @@ pub async fn activate(
         match activate_status.code() {
             Some(0) => (),
             a => {
-                if auto_rollback {
-                    deactivate(&profile_path).await?;
-                }
+                handle_failure(
+                    &profile_path,
+                    &mut state,
+                    auto_rollback,
+                    boot,
+                    native_profiles,
+                )
+                .await?;
                 return Err(ActivateError::RunActivateExit(a));
             }
         };
@@ -466,21 +1124,293 @@ pub async fn activate(
         if !dry_activate {
             info!("Activation succeeded!");
         }
+        record_state(&mut state, ActivationState::Activated);
+
+        // confirmed by the boot-time check once the node rebooted into it
+        if boot && boot_check {
+            info!(
+                "Armed the boot-time check, confirm the new generation within {}s of booting into it",
+                confirm_timeout
+            );
+            record_state(&mut state, ActivationState::AwaitingBoot);
+            return Ok(());
+        }
 
-        if magic_rollback && !boot {
+        if let Some(token) = confirm_token {
             info!("Magic rollback is enabled, setting up confirmation hook...");
-            if let Err(err) = activation_confirmation(temp_path, confirm_timeout, closure).await {
-                deactivate(&profile_path).await?;
+            record_state(&mut state, ActivationState::AwaitingConfirm);
+            if let Err(err) = activation_confirmation(
+                temp_path,
+                confirm_timeout,
+                confirm_timeout_max,
+                closure,
+                token,
+            )
+            .await
+            {
+                handle_failure(&profile_path, &mut state, true, boot, native_profiles).await?;
                 return Err(ActivateError::ActivationConfirmation(err));
             }
         }
+        if let Some(state) = &mut state
+            && let Err(e) = state.disarm_boot_check()
+        {
+            warn!("{}", e);
+        }
+        record_state(&mut state, ActivationState::Confirmed);
+    }
+
+    Ok(())
+}
+
+async fn stage_secrets(temp_path: PathBuf, closure: String) -> Result<(), SecretsError> {
+    let mut payload = Vec::new();
+    std::io::stdin()
+        .read_to_end(&mut payload)
+        .map_err(|e| SecretsError::ReadStaged(PathBuf::from("<stdin>"), e))?;
+
+    let staged: Vec<secrets::StagedSecret> = serde_json::from_slice(&payload)?;
+    secrets::stage_secrets(&secrets::make_secrets_path(&temp_path, &closure), &staged)?;
+
+    info!("Staged {} secret(s) for activation", staged.len());
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum StatusError {
+    #[error("Failed to resolve profile {0}: {1}")]
+    Resolve(String, std::io::Error),
+    #[error("Profile {0} points to {1}, not {2}")]
+    NotActive(String, String, String),
+    #[error("Activation of {0} hasn't been confirmed")]
+    NotConfirmed(String),
+}
+
+async fn status(
+    profile_path: String,
+    closure: String,
+    temp_path: PathBuf,
+) -> Result<(), StatusError> {
+    let active = fs::canonicalize(&profile_path)
+        .await
+        .map_err(|e| StatusError::Resolve(profile_path.clone(), e))?;
+
+    if active != Path::new(&closure) {
+        return Err(StatusError::NotActive(
+            profile_path,
+            active.display().to_string(),
+            closure,
+        ));
+    }
+
+    // the activation only stops listening once it was confirmed or rolled back
+    if confirmation::is_waiting(&temp_path, &closure).await {
+        return Err(StatusError::NotConfirmed(closure));
+    }
+
+    info!("{} is the active generation of {}", closure, profile_path);
+
+    Ok(())
+}
+
+async fn revoke(profile_path: String, native_profiles: bool) -> Result<(), DeactivateError> {
+    let mut state = StateFile::open(&profile_path).unwrap_or_else(|e| {
+        warn!("{}", e);
+        None
+    });
+
+    // the recorded activation is only what to revoke while its closure is still active
+    let active = fs::canonicalize(&profile_path).await.ok();
+    let record = state
+        .as_ref()
+        .map(|x| x.record())
+        .filter(|x| active.as_deref() == Some(Path::new(&x.closure)));
+    let native_profiles = record
+        .and_then(|x| x.native_profiles)
+        .unwrap_or(native_profiles);
+    deactivate(profile_path.as_str(), false, record, native_profiles).await?;
+
+    record_state(&mut state, ActivationState::RolledBack);
+
+    Ok(())
+}
+
+async fn print_state(profile_path: String) -> Result<(), StateError> {
+    let record = StateFile::open(&profile_path)?.map(|x| x.record().clone());
+    // only the JSON goes to stdout, for the deployer to parse
+    println!(
+        "{}",
+        serde_json::to_string(&record).expect("states can be serialized")
+    );
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RecoverError {
+    #[error("Failed to look for profiles with a recorded activation state: {0}")]
+    FindProfiles(std::io::Error),
+    #[error("{0}")]
+    State(#[from] StateError),
+    #[error("{0}")]
+    Lock(#[from] AcquireLockError),
+    #[error("Failed to roll back: {0}")]
+    Deactivate(#[from] DeactivateError),
+    #[error("Failed to run the reboot command: {0}")]
+    Reboot(std::io::Error),
+    #[error("The reboot command resulted in a bad exit code: {0:?}")]
+    RebootExit(Option<i32>),
+    #[error("Failed to recover profile(s) {0}")]
+    Failed(String),
+}
+
+/// Waits for the deployer to confirm the generation the node booted into, otherwise rolls back to
+/// the previous generation and reboots into it
+async fn boot_check(
+    profile_path: &str,
+    mut state: StateFile,
+    confirm_timeout: u16,
+    native_profiles: bool,
+) -> Result<(), RecoverError> {
+    let record = state.record().clone();
+
+    // the lock of the activation from before the reboot is taken over
+    let (user, host) = deploy::lock::deployer();
+    let _lock = ProfileLock::acquire(profile_path, &LockOwner::new(user, host, None), true)?;
+
+    info!(
+        "Waiting for the deployer to confirm {} in {} after booting",
+        record.closure, profile_path
+    );
+    state.transition(ActivationState::AwaitingConfirm)?;
+
+    let confirmation = match state.boot_token()? {
+        Some(token) => activation_confirmation(
+            record.temp_path.clone(),
+            confirm_timeout,
+            None,
+            record.closure.clone(),
+            token,
+        )
+        .await
+        .map_err(|e| e.to_string()),
+        None => Err("its confirmation token is gone".to_string()),
+    };
+    state.disarm_boot_check()?;
+
+    match confirmation {
+        Ok(()) => {
+            info!("The boot into {} was confirmed", record.closure);
+            state.transition(ActivationState::Confirmed)?;
+        }
+        Err(e) => {
+            warn!(
+                "The boot into {} in {} wasn't confirmed ({}), rolling back and rebooting",
+                record.closure, profile_path, e
+            );
+            deactivate(profile_path, true, Some(&record), native_profiles).await?;
+            state.transition(ActivationState::RolledBack)?;
+
+            let reboot_exit_status = Command::new("reboot")
+                .status()
+                .await
+                .map_err(RecoverError::Reboot)?;
+            match reboot_exit_status.code() {
+                Some(0) => (),
+                a => return Err(RecoverError::RebootExit(a)),
+            };
+        }
+    }
+
+    Ok(())
+}
+
+async fn recover_profile(profile_path: &str, native_profiles: bool) -> Result<(), RecoverError> {
+    let Some(mut state) = StateFile::open(profile_path)? else {
+        info!("No activation of {} was recorded", profile_path);
+        return Ok(());
+    };
+    let record = state.record().clone();
+    // rolled back the way the activation set the profile, whatever this run was given
+    let native_profiles = record.native_profiles.unwrap_or(native_profiles);
+
+    match record.current().map(|x| x.state) {
+        // still armed, so the activation never got to be confirmed before the reboot
+        Some(
+            ActivationState::SetProfile
+            | ActivationState::Activated
+            | ActivationState::AwaitingBoot
+            | ActivationState::AwaitingConfirm,
+        ) if record.boot_check.is_some() => {
+            if confirmation::is_waiting(&record.temp_path, &record.closure).await {
+                info!(
+                    "The activation of {} is still waiting for confirmation, leaving it",
+                    profile_path
+                );
+                return Ok(());
+            }
+
+            let confirm_timeout = record.boot_check.unwrap_or_default();
+            boot_check(profile_path, state, confirm_timeout, native_profiles).await?;
+        }
+        Some(ActivationState::AwaitingConfirm) => {
+            // an activation that is still running may well be confirmed yet
+            if confirmation::is_waiting(&record.temp_path, &record.closure).await {
+                info!(
+                    "The activation of {} is still waiting for confirmation, leaving it",
+                    profile_path
+                );
+                return Ok(());
+            }
+
+            warn!(
+                "The activation of {} in {} was left waiting for confirmation, rolling it back",
+                record.closure, profile_path
+            );
+            // the lock of the activation that died is taken over
+            let (user, host) = deploy::lock::deployer();
+            let _lock =
+                ProfileLock::acquire(profile_path, &LockOwner::new(user, host, None), true)?;
+            deactivate(profile_path, false, Some(&record), native_profiles).await?;
+            state.transition(ActivationState::RolledBack)?;
+        }
+        Some(
+            x @ (ActivationState::SetProfile
+            | ActivationState::Activated
+            | ActivationState::AwaitingBoot),
+        ) => warn!(
+            "The activation of {} in {} was left in state {}, check the profile by hand",
+            record.closure, profile_path, x
+        ),
+        x => debug!("Nothing to recover for {} ({:?})", profile_path, x),
     }
 
     Ok(())
 }
 
-async fn revoke(profile_path: String) -> Result<(), DeactivateError> {
-    deactivate(profile_path.as_str()).await?;
+async fn recover(profile_path: Option<String>, native_profiles: bool) -> Result<(), RecoverError> {
+    let profile_paths = match profile_path {
+        Some(x) => vec![x],
+        None => {
+            let nix_state_dir = env::var("NIX_STATE_DIR").unwrap_or("/nix/var/nix".to_string());
+            deploy::state::find_recorded_profiles(Path::new(&nix_state_dir))
+                .map_err(RecoverError::FindProfiles)?
+        }
+    };
+
+    // one profile failing to recover doesn't keep the others from recovering
+    let mut failed = Vec::new();
+    for profile_path in profile_paths {
+        if let Err(e) = recover_profile(&profile_path, native_profiles).await {
+            error!("Failed to recover {}: {}", profile_path, e);
+            failed.push(profile_path);
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(RecoverError::Failed(failed.join(", ")));
+    }
+
     Ok(())
 }
 
@@ -553,6 +1483,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         SubCommand::Activate(..) => LoggerType::Activate,
         SubCommand::Wait(..) => LoggerType::Wait,
         SubCommand::Revoke(..) => LoggerType::Revoke,
+        SubCommand::StageSecrets(..) => LoggerType::Activate,
+        SubCommand::Status(..) => LoggerType::Activate,
+        SubCommand::Confirm(..) => LoggerType::Activate,
+        SubCommand::Reject(..) => LoggerType::Revoke,
+        SubCommand::Extend(..) => LoggerType::Activate,
+        SubCommand::State(..) => LoggerType::Activate,
+        SubCommand::Recover(..) => LoggerType::Revoke,
     };
     init_logger(
         opts.debug_logs,
@@ -562,7 +1499,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     )?;
 
     let r = match opts.subcmd {
-        SubCommand::Activate(activate_opts) => activate(
+        SubCommand::Activate(activate_opts) => match activate(
             get_profile_path(
                 activate_opts.profile_path,
                 activate_opts.profile_user,
@@ -572,13 +1509,41 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             activate_opts.auto_rollback,
             activate_opts.temp_path,
             activate_opts.confirm_timeout,
+            activate_opts.confirm_timeout_max,
             activate_opts.magic_rollback,
             activate_opts.dry_activate,
             activate_opts.boot,
             activate_opts.test,
+            activate_opts.script_timeout,
+            activate_opts.boot_check,
+            activate_opts.secrets,
+            LockOwner::new(
+                activate_opts
+                    .deployer_user
+                    .unwrap_or_else(|| "unknown".to_string()),
+                activate_opts
+                    .deployer_host
+                    .unwrap_or_else(|| "unknown".to_string()),
+                activate_opts.run_id,
+            ),
+            activate_opts.force_unlock,
+            activate_opts.native_profiles,
         )
         .await
-        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+        {
+            // distinct exit codes, so that the deployer can tell these apart from a failed activation
+            Err(ActivateError::Lock(
+                err @ (AcquireLockError::Locked(..) | AcquireLockError::LockedUnknown(..)),
+            )) => {
+                error!("{}", err);
+                std::process::exit(deploy::deploy::LOCKED_EXIT_CODE)
+            }
+            Err(err @ ActivateError::ScriptTimeout(_)) => {
+                error!("{}", err);
+                std::process::exit(deploy::deploy::SCRIPT_TIMEOUT_EXIT_CODE)
+            }
+            r => r.map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+        },
 
         SubCommand::Wait(wait_opts) => wait(
             wait_opts.temp_path,
@@ -588,13 +1553,72 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .await
         .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
 
-        SubCommand::Revoke(revoke_opts) => revoke(get_profile_path(
-            revoke_opts.profile_path,
-            revoke_opts.profile_user,
-            revoke_opts.profile_name,
+        SubCommand::StageSecrets(stage_secrets_opts) => {
+            stage_secrets(stage_secrets_opts.temp_path, stage_secrets_opts.closure)
+                .await
+                .map_err(|x| Box::new(x) as Box<dyn std::error::Error>)
+        }
+
+        SubCommand::Status(status_opts) => status(
+            get_profile_path(
+                status_opts.profile_path,
+                status_opts.profile_user,
+                status_opts.profile_name,
+            )?,
+            status_opts.closure,
+            status_opts.temp_path,
+        )
+        .await
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::Confirm(confirm_opts) => confirm(confirm_opts.temp_path, confirm_opts.closure)
+            .await
+            .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::State(state_opts) => print_state(get_profile_path(
+            state_opts.profile_path,
+            state_opts.profile_user,
+            state_opts.profile_name,
         )?)
         .await
         .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::Recover(recover_opts) => recover(
+            match (recover_opts.profile_path, recover_opts.profile_user) {
+                (None, None) => None,
+                (profile_path, profile_user) => Some(get_profile_path(
+                    profile_path,
+                    profile_user,
+                    recover_opts.profile_name,
+                )?),
+            },
+            recover_opts.native_profiles,
+        )
+        .await
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::Extend(extend_opts) => extend(
+            extend_opts.temp_path,
+            extend_opts.closure,
+            extend_opts.seconds,
+        )
+        .await
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::Reject(reject_opts) => reject(reject_opts.temp_path, reject_opts.closure)
+            .await
+            .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::Revoke(revoke_opts) => revoke(
+            get_profile_path(
+                revoke_opts.profile_path,
+                revoke_opts.profile_user,
+                revoke_opts.profile_name,
+            )?,
+            revoke_opts.native_profiles,
+        )
+        .await
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
     };
 
     match r {
@@ -607,3 +1631,142 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_script_timeout_kills_process_group() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("deploy-rs-script-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("deploy-rs-activate");
+    std::fs::write(
+        &script,
+        "#!/bin/sh\nsh -c 'sleep 100 & echo $! > background; sleep 100'\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let result =
+        run_activation_script(&dir.display().to_string(), false, false, false, Some(1)).await;
+    assert!(matches!(result, Err(ActivateError::ScriptTimeout(1))));
+
+    // the background sleep was never waited for by the script, it may linger as a zombie at most
+    let pid = std::fs::read_to_string(dir.join("background")).unwrap();
+    let alive = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())).is_ok_and(|x| {
+        !x.rsplit(')')
+            .next()
+            .unwrap_or("")
+            .trim_start()
+            .starts_with('Z')
+    });
+    assert!(!alive);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_roll_back_to_native() {
+    let dir = std::env::temp_dir().join(format!("deploy-rs-rollback-test-{}", std::process::id()));
+    let nix_state_dir = dir.join("state");
+    std::fs::create_dir_all(nix_state_dir.join("gcroots/auto")).unwrap();
+    let closure = |name: &str| {
+        let path = dir.join(name);
+        std::fs::create_dir_all(&path).unwrap();
+        path.display().to_string()
+    };
+    let (a, b, c) = (closure("a"), closure("b"), closure("c"));
+    let profile_path = dir.join("profiles/home").display().to_string();
+
+    profile::set_profile(&profile_path, &a, &nix_state_dir).unwrap();
+    profile::set_profile(&profile_path, &b, &nix_state_dir).unwrap();
+    let previous = current_generation(&profile_path, true)
+        .await
+        .unwrap()
+        .unwrap();
+    profile::set_profile(&profile_path, &c, &nix_state_dir).unwrap();
+
+    roll_back_to(&profile_path, &previous, Some(3), true)
+        .await
+        .unwrap();
+    assert_eq!(
+        current_generation(&profile_path, true).await.unwrap(),
+        Some(previous.clone())
+    );
+    assert_eq!(
+        latest_generation(&profile_path, true).await.unwrap(),
+        Some(2)
+    );
+
+    // after rolling back from 2 to 1, deploying the closure of 2 again reuses generation 2
+    profile::switch_generation(&profile_path, 1).unwrap();
+    let previous = current_generation(&profile_path, true)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        profile::set_profile(&profile_path, &b, &nix_state_dir).unwrap(),
+        2
+    );
+    roll_back_to(&profile_path, &previous, None, true)
+        .await
+        .unwrap();
+    assert_eq!(
+        current_generation(&profile_path, true)
+            .await
+            .unwrap()
+            .unwrap()
+            .number,
+        1
+    );
+    assert_eq!(
+        latest_generation(&profile_path, true).await.unwrap(),
+        Some(2)
+    );
+
+    let gone = Generation {
+        number: 1,
+        path: c.clone(),
+    };
+    assert!(matches!(
+        roll_back_to(&profile_path, &gone, None, true).await,
+        Err(DeactivateError::GenerationGone(1, _))
+    ));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_confirmation_extension_limit() {
+    let temp_path =
+        std::env::temp_dir().join(format!("deploy-rs-confirm-test-{}", std::process::id()));
+    let closure = "/nix/store/aaaaaaaa-test".to_string();
+    let started = std::time::Instant::now();
+    let waiting = tokio::spawn(activation_confirmation(
+        temp_path.clone(),
+        1,
+        Some(2),
+        closure.clone(),
+        "token".to_string(),
+    ));
+
+    while !confirmation::is_waiting(&temp_path, &closure).await {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let request = Request {
+        nonce: "token".to_string(),
+        command: confirmation::Command::Extend { seconds: 60 },
+    };
+    confirmation::send_request(&temp_path, &closure, &request)
+        .await
+        .unwrap();
+
+    // extended up to `confirm_timeout_max` after the activation started waiting, not by 60s
+    let result = timeout(Duration::from_secs(10), waiting)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(result, Err(ActivationConfirmationError::TimesUp)));
+    assert!(started.elapsed() >= Duration::from_secs(2));
+
+    std::fs::remove_dir_all(&temp_path).unwrap();
+}
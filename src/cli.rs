@@ -13,11 +13,13 @@ use tokio::join;
 
 use crate::{self as deploy};
 
+use self::deploy::config::{Config, ConfigFiles};
 use self::deploy::{DeployFlake, ParseFlakeError};
 use futures_util::stream::{StreamExt, TryStreamExt};
 use log::{debug, error, info, warn};
+use merge::Merge;
 use serde::Serialize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use thiserror::Error;
 use tokio::process::Command;
@@ -38,39 +40,64 @@ pub struct Opts {
     #[clap(short, long)]
     file: Option<String>,
     /// Check signatures when using `nix copy`
-    #[arg(short, long)]
+    #[arg(short, long, overrides_with = "no_checksigs")]
     checksigs: bool,
+    #[arg(long, overrides_with = "checksigs", hide = true)]
+    no_checksigs: bool,
     /// Use the interactive prompt before deployment
-    #[arg(short, long)]
+    #[arg(short, long, overrides_with = "no_interactive")]
     interactive: bool,
+    #[arg(long, overrides_with = "interactive", hide = true)]
+    no_interactive: bool,
     /// Extra arguments to be passed to nix build
     #[arg(last = true)]
     extra_build_args: Vec<String>,
 
     /// Print debug logs to output
-    #[arg(short, long)]
+    #[arg(short, long, overrides_with = "no_debug_logs")]
     debug_logs: bool,
+    #[arg(long, overrides_with = "debug_logs", hide = true)]
+    no_debug_logs: bool,
     /// Directory to print logs to (including the background activation process)
     #[arg(long)]
     log_dir: Option<String>,
     /// Disable emoji in log output
-    #[arg(long)]
+    #[arg(long, overrides_with = "emoji")]
     no_emoji: bool,
+    #[arg(long, overrides_with = "no_emoji", hide = true)]
+    emoji: bool,
 
     /// Keep the build outputs of each built profile
-    #[arg(short, long)]
+    #[arg(short, long, overrides_with = "no_keep_result")]
     keep_result: bool,
+    #[arg(long, overrides_with = "keep_result", hide = true)]
+    no_keep_result: bool,
     /// Location to keep outputs from built profiles in
     #[arg(short, long)]
     result_path: Option<String>,
 
     /// Skip the automatic pre-build checks
-    #[arg(short, long)]
+    #[arg(short, long, overrides_with = "no_skip_checks")]
     skip_checks: bool,
+    #[arg(long, overrides_with = "skip_checks", hide = true)]
+    no_skip_checks: bool,
+    /// Skip checking that the nodes are reachable and ready for deployment before building
+    #[arg(long, overrides_with = "no_skip_preflight")]
+    skip_preflight: bool,
+    #[arg(long, overrides_with = "skip_preflight", hide = true)]
+    no_skip_preflight: bool,
 
     /// Build on remote host
-    #[arg(long)]
+    #[arg(long, overrides_with = "no_remote_build")]
     remote_build: bool,
+    #[arg(long, overrides_with = "remote_build", hide = true)]
+    no_remote_build: bool,
+
+    /// Skip profiles whose node already has the closure active and confirmed
+    #[arg(long, overrides_with = "no_skip_unchanged")]
+    skip_unchanged: bool,
+    #[arg(long, overrides_with = "skip_unchanged", hide = true)]
+    no_skip_unchanged: bool,
 
     /// Override the SSH user with the given value
     #[arg(long)]
@@ -96,12 +123,26 @@ pub struct Opts {
     /// Make activation wait for confirmation, or roll back after a period of time
     #[arg(long)]
     magic_rollback: Option<bool>,
+    /// Keep magic rollback going across reboots, with a boot-time check on the node (also reboots
+    /// the node after `--boot`)
+    #[arg(long)]
+    boot_check: Option<bool>,
+    /// Manage profile generations on the node natively rather than with `nix-env`
+    #[arg(long)]
+    native_profiles: Option<bool>,
     /// How long activation should wait for confirmation (if using magic-rollback)
     #[arg(long)]
     confirm_timeout: Option<u16>,
+    /// Keep extending the confirmation window with heartbeats while the deployment is still working
+    /// towards confirming it, up to this many seconds in total (if using magic-rollback)
+    #[arg(long)]
+    confirm_timeout_max: Option<u16>,
     /// How long we should wait for profile activation
     #[arg(long)]
     activation_timeout: Option<u16>,
+    /// How long the activation script may run before it is killed and the profile rolled back
+    #[arg(long)]
+    script_timeout: Option<u16>,
     /// Where to store temporary files (only used by magic-rollback)
     #[arg(long)]
     temp_path: Option<PathBuf>,
@@ -123,6 +164,152 @@ pub struct Opts {
     /// Prompt for sudo password during activation.
     #[arg(long)]
     interactive_sudo: Option<bool>,
+    /// Override a setting for a single node or profile, e.g. `--set node.sshUser=admin` or
+    /// `--set node.profile.confirmTimeout=60` (may be repeated)
+    #[arg(long = "set", value_name = "PATH=VALUE")]
+    set: Vec<deploy::SettingOverride>,
+    /// Print the effective settings of each profile and where they came from, without deploying
+    #[arg(long)]
+    explain: bool,
+    /// Print the activation state each profile's node recorded (e.g. whether it is awaiting
+    /// confirmation), without deploying
+    #[arg(long, conflicts_with = "explain")]
+    activation_state: bool,
+    /// Take over the deployment lock of profiles that another deployment holds, e.g. after it crashed
+    #[arg(long)]
+    force_unlock: bool,
+    /// Continue an interrupted run, skipping profiles it already deployed that are still active
+    #[arg(long, value_name = "RUN_ID")]
+    resume: Option<String>,
+    /// What to do with an activation waiting for confirmation when interrupted with Ctrl-C
+    #[arg(long, value_enum)]
+    on_interrupt: Option<deploy::interrupt::InterruptAction>,
+
+    #[command(subcommand)]
+    subcmd: Option<SubCommand>,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum SubCommand {
+    /// Inspect the deployer configuration (`deploy.toml`)
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum ConfigCommand {
+    /// Print the configuration resolved from the command line and configuration files
+    Show,
+}
+
+/// A boolean flag given as `--flag` or `--no-flag`, unset if neither was given
+fn flag(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+impl Opts {
+    /// The options given on the command line, in the same shape as `deploy.toml`
+    fn as_config(&self) -> Config {
+        Config {
+            checksigs: flag(self.checksigs, self.no_checksigs),
+            interactive: flag(self.interactive, self.no_interactive),
+            extra_build_args: (!self.extra_build_args.is_empty())
+                .then(|| self.extra_build_args.clone()),
+            debug_logs: flag(self.debug_logs, self.no_debug_logs),
+            log_dir: self.log_dir.clone(),
+            no_emoji: flag(self.no_emoji, self.emoji),
+            keep_result: flag(self.keep_result, self.no_keep_result),
+            result_path: self.result_path.clone(),
+            skip_checks: flag(self.skip_checks, self.no_skip_checks),
+            skip_preflight: flag(self.skip_preflight, self.no_skip_preflight),
+            remote_build: flag(self.remote_build, self.no_remote_build),
+            skip_unchanged: flag(self.skip_unchanged, self.no_skip_unchanged),
+            ssh_user: self.ssh_user.clone(),
+            profile_user: self.profile_user.clone(),
+            ssh_opts: self.ssh_opts.clone(),
+            compress: self.compress,
+            fast_connection: self.fast_connection,
+            auto_rollback: self.auto_rollback,
+            magic_rollback: self.magic_rollback,
+            boot_check: self.boot_check,
+            native_profiles: self.native_profiles,
+            confirm_timeout: self.confirm_timeout,
+            confirm_timeout_max: self.confirm_timeout_max,
+            activation_timeout: self.activation_timeout,
+            script_timeout: self.script_timeout,
+            temp_path: self.temp_path.clone(),
+            rollback_succeeded: self.rollback_succeeded,
+            sudo: self.sudo.clone(),
+            interactive_sudo: self.interactive_sudo,
+            on_interrupt: self.on_interrupt,
+        }
+    }
+
+    /// Replaces the options with the resolved configuration, as returned by `resolve_config`
+    fn apply_config(&mut self, config: Config) {
+        self.checksigs = config.checksigs.unwrap_or(false);
+        self.interactive = config.interactive.unwrap_or(false);
+        self.extra_build_args = config.extra_build_args.unwrap_or_default();
+        self.debug_logs = config.debug_logs.unwrap_or(false);
+        self.log_dir = config.log_dir;
+        self.no_emoji = config.no_emoji.unwrap_or(false);
+        self.keep_result = config.keep_result.unwrap_or(false);
+        self.result_path = config.result_path;
+        self.skip_checks = config.skip_checks.unwrap_or(false);
+        self.skip_preflight = config.skip_preflight.unwrap_or(false);
+        self.remote_build = config.remote_build.unwrap_or(false);
+        self.skip_unchanged = config.skip_unchanged.unwrap_or(false);
+        self.ssh_user = config.ssh_user;
+        self.profile_user = config.profile_user;
+        self.ssh_opts = config.ssh_opts;
+        self.compress = config.compress;
+        self.fast_connection = config.fast_connection;
+        self.auto_rollback = config.auto_rollback;
+        self.magic_rollback = config.magic_rollback;
+        self.boot_check = config.boot_check;
+        self.native_profiles = config.native_profiles;
+        self.confirm_timeout = config.confirm_timeout;
+        self.confirm_timeout_max = config.confirm_timeout_max;
+        self.activation_timeout = config.activation_timeout;
+        self.script_timeout = config.script_timeout;
+        self.temp_path = config.temp_path;
+        self.rollback_succeeded = config.rollback_succeeded;
+        self.sudo = config.sudo;
+        self.interactive_sudo = config.interactive_sudo;
+        self.on_interrupt = config.on_interrupt;
+    }
+}
+
+/// Resolves the configuration with the precedence: command line > project file > user file
+fn resolve_config(opts: &Opts, files: &ConfigFiles) -> Config {
+    let mut config = opts.as_config();
+    config.merge(files.merged());
+    config
+}
+
+/// The keys set in `config`, as they are spelled in `deploy.toml`
+fn config_keys(config: &Config) -> Vec<String> {
+    match toml::Value::try_from(config) {
+        Ok(toml::Value::Table(table)) => table.keys().cloned().collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn show_config(opts: &Opts, files: &ConfigFiles) -> Result<(), toml::ser::Error> {
+    for (name, file) in [("project", &files.project), ("user", &files.user)] {
+        match file {
+            Some((path, _)) => println!("# Using {} configuration from {}", name, path.display()),
+            None => println!("# No {} configuration file found", name),
+        }
+    }
+
+    print!("{}", toml::to_string(&resolve_config(opts, files))?);
+
+    Ok(())
 }
 
 /// Returns if the available Nix installation supports flakes
@@ -319,7 +506,7 @@ fn print_deployment(
                     user: &defs.profile_user,
                     ssh_user: &defs.ssh_user,
                     path: &data.profile.profile_settings.path,
-                    hostname: &data.node.node_settings.hostname,
+                    hostname: data.hostname(),
                     ssh_opts: &data.merged_settings.ssh_opts,
                 },
             );
@@ -397,6 +584,104 @@ fn prompt_deployment(
     Ok(())
 }
 
+#[derive(Error, Debug)]
+pub enum GetSudoPasswordError {
+    #[error("Failed to run sudo password command for node {0}: {1}")]
+    Command(String, std::io::Error),
+    #[error("Sudo password command for node {0} resulted in a bad exit code: {1:?}")]
+    CommandExit(String, Option<i32>),
+    #[error("Sudo password command output for node {0} contained an invalid UTF-8 sequence: {1}")]
+    CommandUtf8(String, std::string::FromUtf8Error),
+}
+
+/// Name of the environment variable which may hold the sudo password for a node
+fn sudo_password_env_var(node_name: &str) -> String {
+    let node_name: String = node_name
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+    format!("DEPLOY_SUDO_PASSWORD_{}", node_name)
+}
+
+#[test]
+fn test_negated_flags() {
+    let config = |args: &[&str]| {
+        Opts::try_parse_from(["deploy"].iter().chain(args))
+            .unwrap()
+            .as_config()
+    };
+
+    assert_eq!(config(&[]).skip_preflight, None);
+    assert_eq!(config(&["--skip-preflight"]).skip_preflight, Some(true));
+    // overrides `skip-preflight = true` from deploy.toml
+    assert_eq!(config(&["--no-skip-preflight"]).skip_preflight, Some(false));
+    assert_eq!(
+        config(&["--no-skip-preflight", "--skip-preflight"]).skip_preflight,
+        Some(true)
+    );
+    assert_eq!(config(&["--emoji"]).no_emoji, Some(false));
+}
+
+#[test]
+fn test_sudo_password_env_var() {
+    assert_eq!(
+        sudo_password_env_var("my-server.example.com"),
+        "DEPLOY_SUDO_PASSWORD_MY_SERVER_EXAMPLE_COM"
+    );
+}
+
+/// Gets the sudo password for a node from the environment, `sudoPasswordCommand` or the user
+async fn get_sudo_password(
+    deploy_data: &deploy::DeployData,
+    hostname: &str,
+) -> Result<String, GetSudoPasswordError> {
+    let env_var = sudo_password_env_var(&deploy_data.node_name);
+    if let Ok(sudo_password) = std::env::var(&env_var) {
+        debug!("Using sudo password for {} from {}", hostname, env_var);
+        return Ok(sudo_password);
+    }
+
+    if let Some(ref command) = deploy_data.merged_settings.sudo_password_command {
+        debug!("Getting sudo password for {} from `{}`", hostname, command);
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stderr(Stdio::inherit())
+            .output()
+            .await
+            .map_err(|e| GetSudoPasswordError::Command(deploy_data.node_name.clone(), e))?;
+
+        match output.status.code() {
+            Some(0) => (),
+            a => {
+                return Err(GetSudoPasswordError::CommandExit(
+                    deploy_data.node_name.clone(),
+                    a,
+                ));
+            }
+        };
+
+        let sudo_password = String::from_utf8(output.stdout)
+            .map_err(|e| GetSudoPasswordError::CommandUtf8(deploy_data.node_name.clone(), e))?;
+
+        // Password managers usually print a trailing newline
+        return Ok(sudo_password.trim_end_matches(['\r', '\n']).to_string());
+    }
+
+    info!(
+        "You will now be prompted for the sudo password for {}.",
+        hostname
+    );
+    Ok(
+        rpassword::prompt_password(format!("(sudo for {}) Password: ", hostname))
+            .unwrap_or("".to_string()),
+    )
+}
+
 #[derive(Error, Debug)]
 pub enum RunDeployError {
     #[error("Failed to deploy profile {0} to node {1}: {2}")]
@@ -422,6 +707,75 @@ pub enum RunDeployError {
     RevokeProfile(String, String, deploy::deploy::RevokeProfileError),
     #[error("Deployment to node {0} failed, rolled back to previous generation")]
     Rollback(String),
+    #[error("{0}")]
+    SudoPassword(#[from] GetSudoPasswordError),
+    #[error("{0}")]
+    SelectHostname(#[from] deploy::deploy::SelectHostnameError),
+    #[error("{0}")]
+    LoadJournal(#[from] deploy::journal::LoadJournalError),
+    #[error("Failed to check whether profile {0} of node {1} is already deployed: {2}")]
+    IsDeployed(String, String, deploy::deploy::IsDeployedError),
+    #[error("Preflight checks failed for node(s) {0}, use --skip-preflight to deploy anyway")]
+    Preflight(String),
+    #[error("Failed to write the known hosts file for node {0}: {1}")]
+    KnownHosts(String, std::io::Error),
+    #[error(
+        "--hostname can't be used when deploying multiple nodes, use `--set <node>.hostname=<hostname>` instead"
+    )]
+    HostnameMultipleNodes,
+    #[error("Failed to set up handling of Ctrl-C: {0}")]
+    ListenInterrupt(std::io::Error),
+    #[error("Deployment was interrupted")]
+    Interrupted,
+}
+
+/// Logs how far each profile got, for a deployment that was interrupted
+fn interrupted(
+    parts: &[(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData,
+        deploy::DeployDefs,
+    )],
+    journal: &deploy::journal::RunJournal,
+) -> RunDeployError {
+    let recorded = journal.recorded();
+    let rows: Vec<(String, String)> = parts
+        .iter()
+        .map(|(_, deploy_data, _)| {
+            let state = match deploy::journal::last_phase(
+                &recorded,
+                &deploy_data.node_name,
+                &deploy_data.profile_name,
+                &deploy_data.profile.profile_settings.path,
+            ) {
+                None => "not pushed",
+                Some(deploy::journal::Phase::Pushed) => "pushed, not activated",
+                Some(deploy::journal::Phase::Deployed) => "deployed",
+                Some(deploy::journal::Phase::Failed) => "failed",
+                Some(deploy::journal::Phase::RolledBack) => "rolled back",
+            };
+            (
+                format!("{}.{}", deploy_data.node_name, deploy_data.profile_name),
+                state.to_string(),
+            )
+        })
+        .collect();
+
+    warn!(
+        "Deployment interrupted, final state of each profile:\n{}",
+        format_rows(&rows)
+    );
+
+    RunDeployError::Interrupted
+}
+
+/// Lines of profiles and their state, with the states aligned
+fn format_rows(rows: &[(String, String)]) -> String {
+    let width = rows.iter().map(|(x, _)| x.len()).max().unwrap_or(0);
+    rows.iter()
+        .map(|(profile, state)| format!("  {:width$}  {}", profile, state))
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
 type ToDeploy<'a> = Vec<(
@@ -431,6 +785,7 @@ type ToDeploy<'a> = Vec<(
     (&'a str, &'a deploy::data::Profile),
 )>;
 
+#[allow(clippy::too_many_arguments)]
 async fn run_deploy(
     deploy_flakes: Vec<deploy::DeployFlake<'_>>,
     data: Vec<deploy::data::Data>,
@@ -448,6 +803,12 @@ async fn run_deploy(
     log_dir: &Option<String>,
     rollback_succeeded: bool,
     no_emoji: bool,
+    explain: bool,
+    activation_state: bool,
+    skip_preflight: bool,
+    journal: &deploy::journal::RunJournal,
+    resume: bool,
+    interrupt: &deploy::interrupt::Interrupt,
     mp: MultiProgress,
 ) -> Result<(), RunDeployError> {
     let to_deploy: ToDeploy = deploy_flakes
@@ -548,14 +909,46 @@ async fn run_deploy(
         .flatten()
         .collect();
 
+    if cmd_overrides.hostname.is_some() {
+        let mut node_names: Vec<&str> = to_deploy.iter().map(|(_, _, (n, _), _)| *n).collect();
+        node_names.sort_unstable();
+        node_names.dedup();
+        if node_names.len() > 1 {
+            return Err(RunDeployError::HostnameMultipleNodes);
+        }
+    }
+
+    for o in &cmd_overrides.settings {
+        let matches = to_deploy
+            .iter()
+            .any(|(_, _, (node_name, _), (profile_name, _))| {
+                o.node == *node_name && o.profile.as_ref().is_none_or(|p| p == profile_name)
+            });
+        if !matches {
+            warn!(
+                "The override `--set {}` doesn't match any profile being deployed",
+                o
+            );
+        }
+    }
+
     let mut parts: Vec<(
         &deploy::DeployFlake<'_>,
         deploy::DeployData,
         deploy::DeployDefs,
     )> = Vec::new();
 
+    // Sudo passwords by hostname, so that each host is asked for a password at most once
+    let mut sudo_passwords: HashMap<String, String> = HashMap::new();
+
+    // Reachable addresses by node name, so that each node is only probed once
+    let mut hostnames: HashMap<String, String> = HashMap::new();
+
+    // Known hosts files by node name, these have to live until the deployment is done
+    let mut known_hosts_files: HashMap<String, deploy::host_keys::KnownHostsFile> = HashMap::new();
+
     for (deploy_flake, data, (node_name, node), (profile_name, profile)) in to_deploy {
-        let deploy_data = deploy::make_deploy_data(
+        let mut deploy_data = deploy::make_deploy_data(
             &data.generic_settings,
             node,
             node_name.to_string(),
@@ -566,9 +959,62 @@ async fn run_deploy(
             log_dir.clone(),
             no_emoji,
         );
+        deploy_data.run_id = Some(journal.run_id.clone());
+
+        if explain {
+            info!(
+                "Effective settings for profile `{}` of node `{}`:\n{}",
+                profile_name,
+                node_name,
+                deploy::explain::format_explanation(&deploy::explain::explain_settings(
+                    &deploy_data,
+                    &data.generic_settings
+                ))
+            );
+            continue;
+        }
+
+        if !node.node_settings.host_keys.is_empty() {
+            if !known_hosts_files.contains_key(node_name) {
+                let known_hosts = deploy::host_keys::KnownHostsFile::create(
+                    node_name,
+                    &node.node_settings.host_keys,
+                )
+                .map_err(|e| RunDeployError::KnownHosts(node_name.to_string(), e))?;
+                known_hosts_files.insert(node_name.to_string(), known_hosts);
+            }
+
+            // ssh uses the first value given for an option, so these take precedence over sshOpts
+            let mut ssh_opts = known_hosts_files[node_name].ssh_opts();
+            ssh_opts.append(&mut deploy_data.merged_settings.ssh_opts);
+            deploy_data.merged_settings.ssh_opts = ssh_opts;
+        }
 
         let mut deploy_defs = deploy_data.defs()?;
 
+        match hostnames.get(node_name) {
+            Some(hostname) => deploy_data.node.node_settings.hostname = hostname.clone(),
+            None => {
+                deploy::deploy::select_hostname(&mut deploy_data, &deploy_defs.ssh_user).await?;
+                hostnames.insert(
+                    node_name.to_string(),
+                    deploy_data.node.node_settings.hostname.clone(),
+                );
+            }
+        }
+
+        if deploy_data.merged_settings.sudo.is_some()
+            && deploy_data
+                .merged_settings
+                .privilege_escalation
+                .is_some_and(|x| x != deploy::escalation::PrivilegeEscalation::Sudo)
+        {
+            warn!(
+                "The `sudo` setting is ignored for node {} since it doesn't use sudo for privilege escalation",
+                deploy_data.node_name
+            );
+        }
+
         if deploy_data
             .merged_settings
             .interactive_sudo
@@ -582,22 +1028,18 @@ async fn run_deploy(
                 warn!(
                     "Custom sudo commands should be configured to accept password input from stdin when using the 'interactive sudo' option. Deployment may fail if the custom command ignores stdin."
                 );
-            } else {
-                // this configures sudo to hide the password prompt and accept input from stdin
-                // at the time of writing, deploy_defs.sudo defaults to 'sudo -u root' when using user=root and sshUser as non-root
-                let original = deploy_defs.sudo.unwrap_or("sudo".to_string());
-                deploy_defs.sudo = Some(format!("{} -S -p \"\"", original));
             }
 
-            info!(
-                "You will now be prompted for the sudo password for {}.",
-                node.node_settings.hostname
-            );
-            let sudo_password = rpassword::prompt_password(format!(
-                "(sudo for {}) Password: ",
-                node.node_settings.hostname
-            ))
-            .unwrap_or("".to_string());
+            let hostname = deploy_data.hostname().to_string();
+
+            let sudo_password = match sudo_passwords.get(&hostname) {
+                Some(x) => x.clone(),
+                None => {
+                    let sudo_password = get_sudo_password(&deploy_data, &hostname).await?;
+                    sudo_passwords.insert(hostname, sudo_password.clone());
+                    sudo_password
+                }
+            };
 
             deploy_defs.sudo_password = Some(sudo_password);
         }
@@ -605,12 +1047,145 @@ async fn run_deploy(
         parts.push((deploy_flake, deploy_data, deploy_defs));
     }
 
+    if explain {
+        return Ok(());
+    }
+
+    if activation_state {
+        let mut rows = Vec::new();
+        for (_, deploy_data, deploy_defs) in &parts {
+            let state = match deploy::deploy::query_activation_state(deploy_data, deploy_defs).await
+            {
+                Ok(Some(record)) => record.to_string(),
+                Ok(None) => "never activated by deploy-rs".to_string(),
+                Err(e) => format!("unknown: {}", e),
+            };
+            rows.push((
+                format!("{}.{}", deploy_data.node_name, deploy_data.profile_name),
+                state,
+            ));
+        }
+
+        info!("Activation state of each profile:\n{}", format_rows(&rows));
+        return Ok(());
+    }
+
+    let journal_entries = if resume { Some(journal.load()?) } else { None };
+
+    let mut remaining = Vec::new();
+    let mut unchanged: Vec<String> = Vec::new();
+    for part in parts {
+        let (_, deploy_data, deploy_defs) = &part;
+
+        let resumed = journal_entries.as_ref().is_some_and(|entries| {
+            deploy::journal::last_phase(
+                entries,
+                &deploy_data.node_name,
+                &deploy_data.profile_name,
+                &deploy_data.profile.profile_settings.path,
+            ) == Some(deploy::journal::Phase::Deployed)
+        });
+        let skip_unchanged = deploy_data.merged_settings.skip_unchanged.unwrap_or(false);
+
+        let deployed = (resumed || skip_unchanged)
+            && deploy::deploy::is_deployed(deploy_data, deploy_defs)
+                .await
+                .map_err(|e| {
+                    RunDeployError::IsDeployed(
+                        deploy_data.profile_name.to_string(),
+                        deploy_data.node_name.to_string(),
+                        e,
+                    )
+                })?;
+
+        if !deployed {
+            remaining.push(part);
+        } else if resumed {
+            info!(
+                "Profile `{}` of node `{}` was already deployed by run {}, skipping it",
+                deploy_data.profile_name, deploy_data.node_name, journal.run_id
+            );
+        } else {
+            // recorded so that resuming this run skips it as well
+            journal.record(
+                &deploy_data.node_name,
+                &deploy_data.profile_name,
+                &deploy_data.profile.profile_settings.path,
+                deploy::journal::Phase::Deployed,
+            );
+            unchanged.push(format!(
+                "{}.{}",
+                deploy_data.node_name, deploy_data.profile_name
+            ));
+        }
+    }
+    parts = remaining;
+
+    if !unchanged.is_empty() {
+        info!(
+            "Skipping unchanged profiles, their nodes already have them active: {}",
+            unchanged.join(", ")
+        );
+    }
+
+    if parts.is_empty() {
+        info!("Nothing left to deploy");
+        return Ok(());
+    }
+
+    info!(
+        "Run id: {} (journal at {}), continue it with `--resume {}` if it gets interrupted",
+        journal.run_id,
+        journal.path().display(),
+        journal.run_id
+    );
+
+    if !skip_preflight {
+        // profiles grouped by node, so that each node is checked once
+        let mut nodes: Vec<Vec<(&deploy::DeployData, &deploy::DeployDefs)>> = Vec::new();
+        for (_, deploy_data, deploy_defs) in &parts {
+            match nodes
+                .iter_mut()
+                .find(|x| x[0].0.node_name == deploy_data.node_name)
+            {
+                Some(profiles) => profiles.push((deploy_data, deploy_defs)),
+                None => nodes.push(vec![(deploy_data, deploy_defs)]),
+            }
+        }
+
+        info!("Running preflight checks");
+        let results = futures_util::future::join_all(
+            nodes
+                .iter()
+                .map(|profiles| deploy::preflight::preflight_node(profiles)),
+        )
+        .await;
+
+        let table = deploy::preflight::format_preflight(&results);
+        let failed: Vec<&str> = results
+            .iter()
+            .filter(|x| !x.passed())
+            .map(|x| x.node_name.as_str())
+            .collect();
+
+        if !failed.is_empty() {
+            error!("Preflight checks failed:\n{}", table);
+            return Err(RunDeployError::Preflight(failed.join(", ")));
+        }
+        debug!("Preflight checks passed:\n{}", table);
+    }
+
     if interactive {
         prompt_deployment(&parts[..])?;
     } else {
         print_deployment(&parts[..])?;
     }
 
+    // only now, so that Ctrl-C still simply exits while prompting
+    interrupt
+        .listen()
+        .map_err(RunDeployError::ListenInterrupt)?;
+
     let data_iter = || {
         parts.iter().map(
             |(deploy_flake, deploy_data, deploy_defs)| deploy::push::PushProfileData {
@@ -681,6 +1256,8 @@ async fn run_deploy(
             #[allow(clippy::iter_kv_map)]
             for (_, profiles) in remote_build_map {
                 // spawn one future for each host
+                let journal = journal.clone();
+                let interrupt = interrupt.clone();
                 let pb = remote_mp.add(new_spinner());
                 pb.enable_steady_tick(Duration::from_millis(80));
 
@@ -689,6 +1266,11 @@ async fn run_deploy(
 
                     // build profile in order, one after the other
                     for mut profile in profiles {
+                        if interrupt.is_interrupted() {
+                            res = Err(RunDeployError::Interrupted);
+                            break;
+                        }
+
                         let nodename = profile.deploy_data.node_name.clone();
                         let profilename = profile.deploy_data.profile_name.clone();
                         pb.set_prefix(format!(
@@ -713,6 +1295,13 @@ async fn run_deploy(
                         if res.is_err() {
                             break;
                         }
+                        // remotely built profiles are on the node already
+                        journal.record(
+                            &nodename,
+                            &profilename,
+                            &profile.deploy_data.profile.profile_settings.path,
+                            deploy::journal::Phase::Pushed,
+                        );
                     }
 
                     match res {
@@ -737,6 +1326,10 @@ async fn run_deploy(
             let mut set = JoinSet::new();
 
             for mut data in local_builds.into_iter() {
+                if interrupt.is_interrupted() {
+                    break;
+                }
+
                 let pb = mp.add(new_spinner());
                 pb.enable_steady_tick(Duration::from_millis(80));
 
@@ -752,15 +1345,30 @@ async fn run_deploy(
                 });
 
                 match res {
+                    Ok(()) if interrupt.is_interrupted() => {
+                        pb.set_style(finish_style_error());
+                        pb.finish_with_message("Interrupted");
+                    }
                     Ok(()) => {
                         data.deploy_data.progressbar = Some(pb.clone());
+                        let journal = journal.clone();
                         set.spawn(async move {
                             let data = data.clone();
+                            let closure = data.deploy_data.profile.profile_settings.path.clone();
                             pb.set_prefix(format!(
                                 "Pushing profile '{}' to host '{}'",
                                 profile_name, node_name
                             ));
-                            let res = deploy::push::push_profile(data).await.map_err(|e| {
+                            let res = deploy::push::push_profile(data).await;
+                            if res.is_ok() {
+                                journal.record(
+                                    &node_name,
+                                    &profile_name,
+                                    &closure,
+                                    deploy::journal::Phase::Pushed,
+                                );
+                            }
+                            let res = res.map_err(|e| {
                                 RunDeployError::PushProfile(profile_name, node_name, e)
                             });
                             match res {
@@ -790,6 +1398,11 @@ async fn run_deploy(
         }
     );
 
+    // failed builds and pushes are most likely due to Ctrl-C reaching nix as well
+    if interrupt.is_interrupted() {
+        return Err(interrupted(&parts, journal));
+    }
+
     // abort here if any build + push or push + build failed
     for result in remote_results {
         result?
@@ -804,10 +1417,43 @@ async fn run_deploy(
     // the profile's configuration
     let mut succeeded: Vec<(&deploy::DeployData, &deploy::DeployDefs)> = vec![];
     for (_, deploy_data, deploy_defs) in &parts {
-        if let Err(e) =
-            deploy::deploy::deploy_profile(deploy_data, deploy_defs, dry_activate, boot, test).await
+        if interrupt.is_interrupted() {
+            return Err(interrupted(&parts, journal));
+        }
+
+        if let Err(e) = deploy::deploy::deploy_profile(
+            deploy_data,
+            deploy_defs,
+            dry_activate,
+            boot,
+            test,
+            interrupt,
+        )
+        .await
         {
             error!("{}", e);
+            if interrupt.is_interrupted() {
+                // profiles deployed before stay, only the interrupted activation is settled
+                let phase = match e {
+                    deploy::deploy::DeployProfileError::Rejected => {
+                        deploy::journal::Phase::RolledBack
+                    }
+                    _ => deploy::journal::Phase::Failed,
+                };
+                journal.record(
+                    &deploy_data.node_name,
+                    &deploy_data.profile_name,
+                    &deploy_data.profile.profile_settings.path,
+                    phase,
+                );
+                return Err(interrupted(&parts, journal));
+            }
+            journal.record(
+                &deploy_data.node_name,
+                &deploy_data.profile_name,
+                &deploy_data.profile.profile_settings.path,
+                deploy::journal::Phase::Failed,
+            );
             if dry_activate {
                 info!("dry run, not rolling back");
             }
@@ -827,6 +1473,12 @@ async fn run_deploy(
                                     e,
                                 )
                             })?;
+                        journal.record(
+                            &deploy_data.node_name,
+                            &deploy_data.profile_name,
+                            &deploy_data.profile.profile_settings.path,
+                            deploy::journal::Phase::RolledBack,
+                        );
                     }
                 }
                 return Err(RunDeployError::Rollback(deploy_data.node_name.to_string()));
@@ -837,6 +1489,14 @@ async fn run_deploy(
                 e,
             ));
         }
+        if !dry_activate {
+            journal.record(
+                &deploy_data.node_name,
+                &deploy_data.profile_name,
+                &deploy_data.profile.profile_settings.path,
+                deploy::journal::Phase::Deployed,
+            );
+        }
         succeeded.push((deploy_data, deploy_defs))
     }
 
@@ -863,14 +1523,36 @@ pub enum RunError {
     Logger(#[from] flexi_logger::FlexiLoggerError),
     #[error("{0}")]
     RunDeploy(#[from] RunDeployError),
+    #[error("{0}")]
+    LoadConfig(#[from] deploy::config::LoadConfigError),
+    #[error("Failed to print configuration: {0}")]
+    ShowConfig(#[from] toml::ser::Error),
 }
 
 pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
-    let opts = match args {
+    let mut opts = match args {
         Some(o) => <Opts as FromArgMatches>::from_arg_matches(o)?,
         None => Opts::parse(),
     };
 
+    // Configuration files may change logging options, but errors loading them can only be
+    // reported once the logger is running
+    let config_files = deploy::config::load();
+    let mut from_config = Vec::new();
+    if let Ok(ref files) = config_files {
+        if let Some(SubCommand::Config(ConfigCommand::Show)) = opts.subcmd {
+            show_config(&opts, files)?;
+            return Ok(());
+        }
+
+        let resolved = resolve_config(&opts, files);
+        from_config = config_keys(&resolved)
+            .into_iter()
+            .filter(|x| !config_keys(&opts.as_config()).contains(x))
+            .collect();
+        opts.apply_config(resolved);
+    }
+
     let (mp, _handle) = deploy::logging::init_logger(
         opts.debug_logs,
         opts.log_dir.as_deref(),
@@ -878,6 +1560,8 @@ pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
         opts.no_emoji,
     )?;
 
+    config_files?;
+
     let deploys = opts
         .clone()
         .targets
@@ -904,13 +1588,21 @@ pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
         auto_rollback: opts.auto_rollback,
         hostname: opts.hostname,
         magic_rollback: opts.magic_rollback,
+        boot_check: opts.boot_check,
+        native_profiles: opts.native_profiles,
         temp_path: opts.temp_path,
         confirm_timeout: opts.confirm_timeout,
+        confirm_timeout_max: opts.confirm_timeout_max,
         activation_timeout: opts.activation_timeout,
+        script_timeout: opts.script_timeout,
         dry_activate: opts.dry_activate,
         remote_build: opts.remote_build,
+        skip_unchanged: opts.skip_unchanged,
+        force_unlock: opts.force_unlock,
         sudo: opts.sudo,
         interactive_sudo: opts.interactive_sudo,
+        settings: opts.set,
+        from_config,
     };
 
     let supports_flakes = test_flake_support().await.map_err(RunError::FlakeTest)?;
@@ -934,6 +1626,18 @@ pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
         }
     }
     let result_path = opts.result_path.as_deref();
+    let journal = deploy::journal::RunJournal::new(
+        Path::new(
+            opts.log_dir
+                .as_deref()
+                .or(result_path)
+                .unwrap_or("./.deploy-gc"),
+        ),
+        &opts
+            .resume
+            .clone()
+            .unwrap_or_else(deploy::journal::RunJournal::new_run_id),
+    );
     let data = get_deployment_data(using_flakes, &deploy_flakes, &opts.extra_build_args).await?;
     run_deploy(
         deploy_flakes,
@@ -952,9 +1656,18 @@ pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
         &opts.log_dir,
         opts.rollback_succeeded.unwrap_or(true),
         opts.no_emoji,
+        opts.explain,
+        opts.activation_state,
+        opts.skip_preflight,
+        &journal,
+        opts.resume.is_some(),
+        &deploy::interrupt::Interrupt::new(opts.on_interrupt.unwrap_or_default()),
         mp,
     )
     .await?;
 
+    // every profile was deployed, so there is nothing to resume
+    journal.remove();
+
     Ok(())
 }
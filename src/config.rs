@@ -0,0 +1,169 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::debug;
+use merge::Merge;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Name of the configuration file, looked up in the current directory and in
+/// `$XDG_CONFIG_HOME/deploy-rs`
+pub const CONFIG_FILE_NAME: &str = "deploy.toml";
+
+/// Deployer-side defaults for the command line options, every field mirrors a flag of `deploy`
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Merge)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+#[merge(strategy = merge::option::overwrite_none)]
+pub struct Config {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksigs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interactive: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_build_args: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debug_logs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_emoji: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_result: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_checks: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_preflight: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_build: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_unchanged: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssh_user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile_user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssh_opts: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compress: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fast_connection: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auto_rollback: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub magic_rollback: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_check: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub native_profiles: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confirm_timeout: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confirm_timeout_max: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub activation_timeout: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script_timeout: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temp_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rollback_succeeded: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sudo: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interactive_sudo: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_interrupt: Option<crate::interrupt::InterruptAction>,
+}
+
+#[derive(Error, Debug)]
+pub enum LoadConfigError {
+    #[error("Failed to read configuration file {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to parse configuration file {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+}
+
+/// The configuration files that were found, in order of decreasing precedence
+#[derive(Debug, Clone, Default)]
+pub struct ConfigFiles {
+    pub project: Option<(PathBuf, Config)>,
+    pub user: Option<(PathBuf, Config)>,
+}
+
+impl ConfigFiles {
+    /// Merges the files, with the project file taking precedence over the user file
+    pub fn merged(&self) -> Config {
+        let mut config = Config::default();
+        for (_, c) in [&self.project, &self.user].into_iter().flatten() {
+            config.merge(c.clone());
+        }
+        config
+    }
+}
+
+fn load_file(path: &Path) -> Result<Option<Config>, LoadConfigError> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(LoadConfigError::Read(path.to_path_buf(), e)),
+    };
+
+    debug!("Loading configuration from {}", path.display());
+
+    toml::from_str(&contents)
+        .map(Some)
+        .map_err(|e| LoadConfigError::Parse(path.to_path_buf(), e))
+}
+
+pub fn user_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join("deploy-rs").join(CONFIG_FILE_NAME))
+}
+
+/// Loads `deploy.toml` from the current directory and from the user configuration directory
+pub fn load() -> Result<ConfigFiles, LoadConfigError> {
+    let project_path = PathBuf::from(CONFIG_FILE_NAME);
+    let project = load_file(&project_path)?.map(|c| (project_path, c));
+
+    let user = match user_config_path() {
+        Some(user_path) => load_file(&user_path)?.map(|c| (user_path, c)),
+        None => None,
+    };
+
+    Ok(ConfigFiles { project, user })
+}
+
+#[test]
+fn test_config_precedence() {
+    let project: Config = toml::from_str(
+        r#"
+        ssh-user = "project"
+        confirm-timeout = 60
+        "#,
+    )
+    .unwrap();
+    let user: Config = toml::from_str(
+        r#"
+        ssh-user = "user"
+        no-emoji = true
+        extra-build-args = ["--impure"]
+        "#,
+    )
+    .unwrap();
+
+    let files = ConfigFiles {
+        project: Some((PathBuf::from("deploy.toml"), project)),
+        user: Some((PathBuf::from("user.toml"), user)),
+    };
+    let merged = files.merged();
+
+    assert_eq!(merged.ssh_user, Some("project".to_string()));
+    assert_eq!(merged.confirm_timeout, Some(60));
+    assert_eq!(merged.no_emoji, Some(true));
+    assert_eq!(merged.extra_build_args, Some(vec!["--impure".to_string()]));
+
+    assert!(toml::from_str::<Config>("ssh-usr = \"typo\"").is_err());
+}
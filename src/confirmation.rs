@@ -0,0 +1,301 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+const SOCKET_FILE: &str = "socket";
+
+/// Start of the names of the directories holding the confirmation sockets of activations of
+/// `closure`, which end in a random suffix
+fn make_confirmation_dir_prefix(closure: &str) -> String {
+    let lock_hash = &closure["/nix/store/".len()..closure.find('-').unwrap_or(closure.len())];
+    format!("deploy-rs-canary-{}-", lock_hash)
+}
+
+/// What the deployer asks of an activation waiting for confirmation
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Command {
+    /// Keep the new generation
+    Confirm,
+    /// Roll back right away, rather than once the timeout runs out
+    Rollback,
+    /// Wait for confirmation for another `seconds`, counted from now
+    Extend { seconds: u16 },
+}
+
+/// A single line of JSON sent over the confirmation socket
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// The token the deployer gave the activation when starting it
+    pub nonce: String,
+    pub command: Command,
+}
+
+/// The answer of the activation to a request, as a single line of JSON
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Response {
+    Ok,
+    Error(String),
+}
+
+#[derive(Error, Debug)]
+pub enum ConfirmationError {
+    #[error("Failed to generate a confirmation token: {0}")]
+    Token(std::io::Error),
+    #[error("Failed to create the confirmation directory {0}: {1}")]
+    Create(PathBuf, std::io::Error),
+    #[error("Failed to inspect {0}: {1}")]
+    Inspect(PathBuf, std::io::Error),
+    #[error("Refusing to use {0}, it wasn't created by this user: {1}")]
+    NotOurs(PathBuf, &'static str),
+    #[error("No activation of {0} is waiting for confirmation")]
+    NotWaiting(String),
+    #[error("Failed to talk to the waiting activation: {0}")]
+    Socket(std::io::Error),
+    #[error("Failed to encode the request: {0}")]
+    Encode(serde_json::Error),
+    #[error("Invalid response from the waiting activation: {0}")]
+    Decode(serde_json::Error),
+    #[error("The waiting activation refused the request: {0}")]
+    Refused(String),
+}
+
+/// A random token, unpredictable to other users of the node
+pub fn generate_token() -> Result<String, ConfirmationError> {
+    let mut bytes = [0u8; 16];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .map_err(ConfirmationError::Token)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Compares tokens in full, so that the time taken doesn't tell how much of a token was right
+pub fn tokens_match(expected: &str, token: &str) -> bool {
+    let (expected, token) = (expected.as_bytes(), token.as_bytes());
+    !expected.is_empty()
+        && expected.len() == token.len()
+        && expected
+            .iter()
+            .zip(token)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Checks that `path` is owned by the current user and, unless `private_mode` is false, that
+/// nobody else has access to it, so that no other user could have created or tampered with it
+fn check_ours(path: &Path, private_mode: bool) -> Result<fs::Metadata, ConfirmationError> {
+    let metadata = fs::symlink_metadata(path)
+        .map_err(|e| ConfirmationError::Inspect(path.to_path_buf(), e))?;
+
+    if metadata.file_type().is_symlink() {
+        return Err(ConfirmationError::NotOurs(
+            path.to_path_buf(),
+            "it is a symlink",
+        ));
+    }
+    // SAFETY: geteuid can't fail and has no side effects
+    if metadata.uid() != unsafe { libc::geteuid() } {
+        return Err(ConfirmationError::NotOurs(
+            path.to_path_buf(),
+            "it is owned by another user",
+        ));
+    }
+    if private_mode && metadata.mode() & 0o077 != 0 {
+        return Err(ConfirmationError::NotOurs(
+            path.to_path_buf(),
+            "it is accessible to other users",
+        ));
+    }
+
+    Ok(metadata)
+}
+
+/// Creates the private directory for the confirmation socket and returns the path to bind it to.
+///
+/// Its name is only decided here, so that no other user can have created it beforehand.
+pub fn create_confirmation_dir(
+    temp_path: &Path,
+    closure: &str,
+) -> Result<PathBuf, ConfirmationError> {
+    let dir = temp_path.join(format!(
+        "{}{}",
+        make_confirmation_dir_prefix(closure),
+        generate_token()?
+    ));
+
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|e| ConfirmationError::Create(dir.clone(), e))?;
+
+    Ok(dir.join(SOCKET_FILE))
+}
+
+/// The sockets of activations of `closure` in `temp_path`. Directories that other users created,
+/// or that they could have tampered with, are skipped.
+pub fn find_sockets(temp_path: &Path, closure: &str) -> Result<Vec<PathBuf>, ConfirmationError> {
+    let prefix = make_confirmation_dir_prefix(closure);
+    let entries = match fs::read_dir(temp_path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(ConfirmationError::Inspect(temp_path.to_path_buf(), e)),
+    };
+
+    let mut sockets = Vec::new();
+    for entry in entries {
+        let dir = entry
+            .map_err(|e| ConfirmationError::Inspect(temp_path.to_path_buf(), e))?
+            .path();
+        if !dir
+            .file_name()
+            .is_some_and(|x| x.to_string_lossy().starts_with(&prefix))
+        {
+            continue;
+        }
+        let socket = dir.join(SOCKET_FILE);
+
+        // the socket itself is protected by its directory, whatever its mode
+        let checked = check_ours(&dir, true).and_then(|_| check_ours(&socket, false));
+        match checked {
+            Ok(metadata) if metadata.file_type().is_socket() => sockets.push(socket),
+            Ok(_) => log::debug!("Ignoring {}, it is not a socket", socket.display()),
+            // not bound yet, or already gone
+            Err(ConfirmationError::Inspect(_, e)) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => log::debug!("Ignoring confirmation socket: {}", e),
+        }
+    }
+
+    Ok(sockets)
+}
+
+/// Connects to the socket of an activation of `closure` that is listening, if there is one
+async fn connect(temp_path: &Path, closure: &str) -> Result<Option<UnixStream>, ConfirmationError> {
+    for socket in find_sockets(temp_path, closure)? {
+        match UnixStream::connect(&socket).await {
+            Ok(stream) => return Ok(Some(stream)),
+            // a leftover socket of an activation that is gone
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => (),
+            Err(e) => return Err(ConfirmationError::Socket(e)),
+        }
+    }
+    Ok(None)
+}
+
+/// Whether an activation of `closure` is listening for confirmation right now
+pub async fn is_waiting(temp_path: &Path, closure: &str) -> bool {
+    match connect(temp_path, closure).await {
+        Ok(x) => x.is_some(),
+        Err(e) => {
+            log::debug!("Failed to look for a waiting activation: {}", e);
+            false
+        }
+    }
+}
+
+/// Sends `request` to the activation of `closure` waiting for confirmation
+pub async fn send_request(
+    temp_path: &Path,
+    closure: &str,
+    request: &Request,
+) -> Result<(), ConfirmationError> {
+    let mut stream = connect(temp_path, closure)
+        .await?
+        .ok_or_else(|| ConfirmationError::NotWaiting(closure.to_string()))?;
+
+    let mut line = serde_json::to_vec(request).map_err(ConfirmationError::Encode)?;
+    line.push(b'\n');
+    stream
+        .write_all(&line)
+        .await
+        .map_err(ConfirmationError::Socket)?;
+
+    let mut response = String::new();
+    BufReader::new(stream)
+        .read_line(&mut response)
+        .await
+        .map_err(ConfirmationError::Socket)?;
+
+    match serde_json::from_str(&response).map_err(ConfirmationError::Decode)? {
+        Response::Ok => Ok(()),
+        Response::Error(e) => Err(ConfirmationError::Refused(e)),
+    }
+}
+
+#[test]
+fn test_confirmation_protocol() {
+    assert_eq!(
+        serde_json::to_string(&Request {
+            nonce: "abc".to_string(),
+            command: Command::Extend { seconds: 30 },
+        })
+        .unwrap(),
+        r#"{"nonce":"abc","command":{"extend":{"seconds":30}}}"#
+    );
+    assert_eq!(
+        serde_json::from_str::<Request>(r#"{"nonce":"abc","command":"confirm"}"#).unwrap(),
+        Request {
+            nonce: "abc".to_string(),
+            command: Command::Confirm,
+        }
+    );
+    assert_eq!(
+        serde_json::to_string(&Response::Error("wrong nonce".to_string())).unwrap(),
+        r#"{"error":"wrong nonce"}"#
+    );
+
+    let token = generate_token().unwrap();
+    assert_eq!(token.len(), 32);
+    assert!(tokens_match(&token, &token));
+    assert!(!tokens_match(&token, &generate_token().unwrap()));
+    assert!(!tokens_match(&token, ""));
+    assert!(!tokens_match("", ""));
+
+    let temp_path = std::env::temp_dir().join(format!(
+        "deploy-rs-confirmation-test-{}",
+        std::process::id()
+    ));
+    fs::create_dir_all(&temp_path).unwrap();
+    let closure = "/nix/store/abc123-system";
+
+    assert_eq!(
+        find_sockets(&temp_path, closure).unwrap(),
+        Vec::<PathBuf>::new()
+    );
+    let socket = create_confirmation_dir(&temp_path, closure).unwrap();
+    let dir = socket.parent().unwrap();
+    assert!(
+        dir.file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("deploy-rs-canary-abc123-")
+    );
+    // every activation gets a directory of its own
+    assert_ne!(
+        create_confirmation_dir(&temp_path, closure).unwrap(),
+        socket
+    );
+    let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+    assert_eq!(
+        find_sockets(&temp_path, closure).unwrap(),
+        vec![socket.clone()]
+    );
+
+    // a directory others can get into wasn't made by the activator
+    fs::set_permissions(dir, std::os::unix::fs::PermissionsExt::from_mode(0o777)).unwrap();
+    assert_eq!(
+        find_sockets(&temp_path, closure).unwrap(),
+        Vec::<PathBuf>::new()
+    );
+
+    fs::remove_dir_all(&temp_path).unwrap();
+}
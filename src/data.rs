@@ -2,9 +2,11 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
+use crate::escalation::PrivilegeEscalation;
 use merge::Merge;
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::fmt;
 use std::path::PathBuf;
 
 #[derive(Deserialize, Debug, Clone, Merge)]
@@ -40,10 +42,18 @@ pub struct GenericSettings {
     #[merge(strategy = merge::option::overwrite_none)]
     pub confirm_timeout: Option<u16>,
 
+    #[serde(rename(deserialize = "confirmTimeoutMax"))]
+    #[merge(strategy = merge::option::overwrite_none)]
+    pub confirm_timeout_max: Option<u16>,
+
     #[serde(rename(deserialize = "activationTimeout"))]
     #[merge(strategy = merge::option::overwrite_none)]
     pub activation_timeout: Option<u16>,
 
+    #[serde(rename(deserialize = "scriptTimeout"))]
+    #[merge(strategy = merge::option::overwrite_none)]
+    pub script_timeout: Option<u16>,
+
     #[serde(rename(deserialize = "tempPath"))]
     #[merge(strategy = merge::option::overwrite_none)]
     pub temp_path: Option<PathBuf>,
@@ -52,6 +62,14 @@ pub struct GenericSettings {
     #[merge(strategy = merge::option::overwrite_none)]
     pub magic_rollback: Option<bool>,
 
+    #[serde(rename(deserialize = "bootCheck"))]
+    #[merge(strategy = merge::option::overwrite_none)]
+    pub boot_check: Option<bool>,
+
+    #[serde(rename(deserialize = "nativeProfiles"))]
+    #[merge(strategy = merge::option::overwrite_none)]
+    pub native_profiles: Option<bool>,
+
     #[serde(rename(deserialize = "sudo"))]
     #[merge(strategy = merge::option::overwrite_none)]
     pub sudo: Option<String>,
@@ -63,11 +81,142 @@ pub struct GenericSettings {
     #[serde(rename(deserialize = "interactiveSudo"))]
     #[merge(strategy = merge::option::overwrite_none)]
     pub interactive_sudo: Option<bool>,
+
+    #[serde(rename(deserialize = "sudoPasswordCommand"))]
+    #[merge(strategy = merge::option::overwrite_none)]
+    pub sudo_password_command: Option<String>,
+
+    #[serde(rename(deserialize = "privilegeEscalation"))]
+    #[merge(strategy = merge::option::overwrite_none)]
+    pub privilege_escalation: Option<PrivilegeEscalation>,
+
+    #[serde(rename(deserialize = "preserveEnv"))]
+    #[merge(strategy = merge::option::overwrite_none)]
+    pub preserve_env: Option<bool>,
+
+    #[serde(rename(deserialize = "signingKeys"))]
+    #[merge(strategy = merge::option::overwrite_none)]
+    pub signing_keys: Option<Vec<SigningKey>>,
+
+    #[serde(rename(deserialize = "sshPort"))]
+    #[merge(strategy = merge::option::overwrite_none)]
+    pub ssh_port: Option<u16>,
+
+    #[serde(rename(deserialize = "sshIdentityFile"))]
+    #[merge(strategy = merge::option::overwrite_none)]
+    pub ssh_identity_file: Option<PathBuf>,
+
+    #[serde(rename(deserialize = "jumpHosts"))]
+    #[merge(strategy = merge::option::overwrite_none)]
+    pub jump_hosts: Option<Vec<JumpHost>>,
+
+    #[serde(rename(deserialize = "retries"))]
+    #[merge(strategy = merge::option::overwrite_none)]
+    pub retries: Option<u32>,
+
+    #[serde(rename(deserialize = "retryDelay"))]
+    #[merge(strategy = merge::option::overwrite_none)]
+    pub retry_delay: Option<u16>,
+
+    #[serde(rename(deserialize = "skipUnchanged"))]
+    #[merge(strategy = merge::option::overwrite_none)]
+    pub skip_unchanged: Option<bool>,
+}
+
+/// Names of the `GenericSettings` fields as they appear in the deploy definition
+pub const GENERIC_SETTINGS_KEYS: &[&str] = &[
+    "sshUser",
+    "user",
+    "sshOpts",
+    "compress",
+    "fastConnection",
+    "autoRollback",
+    "confirmTimeout",
+    "confirmTimeoutMax",
+    "activationTimeout",
+    "scriptTimeout",
+    "tempPath",
+    "magicRollback",
+    "bootCheck",
+    "nativeProfiles",
+    "sudo",
+    "remoteBuild",
+    "interactiveSudo",
+    "sudoPasswordCommand",
+    "privilegeEscalation",
+    "preserveEnv",
+    "signingKeys",
+    "sshPort",
+    "sshIdentityFile",
+    "jumpHosts",
+    "retries",
+    "retryDelay",
+    "skipUnchanged",
+];
+
+/// A secret key used to sign the closure before it is copied to the target
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum SigningKey {
+    /// Local secret key file
+    File(PathBuf),
+    /// Local command printing the secret key to stdout
+    Command { command: String },
+}
+
+/// A host to connect through (as with `ssh -J`) to reach the node
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JumpHost {
+    pub hostname: String,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+}
+
+impl fmt::Display for JumpHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref user) = self.user {
+            write!(f, "{}@", user)?;
+        }
+        // IPv6 addresses have to be bracketed, ssh splits the host from the port at the first colon
+        write!(f, "{}", crate::bracket_host(&self.hostname))?;
+        match self.port {
+            Some(port) => write!(f, ":{}", port),
+            None => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_jump_host_display() {
+    let jump_host = |hostname: &str, user: Option<&str>, port| JumpHost {
+        hostname: hostname.to_string(),
+        user: user.map(str::to_string),
+        port,
+    };
+
+    assert_eq!(jump_host("bastion", None, None).to_string(), "bastion");
+    assert_eq!(
+        jump_host("bastion", Some("jump"), Some(2222)).to_string(),
+        "jump@bastion:2222"
+    );
+    assert_eq!(
+        jump_host("fd00::1", Some("jump"), Some(22)).to_string(),
+        "jump@[fd00::1]:22"
+    );
+    assert_eq!(
+        jump_host("fd00::1", Some("jump"), None).to_string(),
+        "jump@[fd00::1]"
+    );
+    assert_eq!(jump_host("[fd00::1]", None, None).to_string(), "[fd00::1]");
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct NodeSettings {
+    #[serde(default)]
     pub hostname: String,
+    /// Further addresses of the node, tried in order after `hostname` when it's unreachable
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub hostnames: Vec<String>,
     pub profiles: HashMap<String, Profile>,
     #[serde(
         skip_serializing_if = "Vec::is_empty",
@@ -75,6 +224,25 @@ pub struct NodeSettings {
         rename(deserialize = "profilesOrder")
     )]
     pub profiles_order: Vec<String>,
+    /// Public keys of the node, when set these are the only host keys ssh accepts
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        default,
+        rename(deserialize = "hostKeys")
+    )]
+    pub host_keys: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Secret {
+    /// Local file to read the secret from
+    pub source: Option<PathBuf>,
+    /// Local command printing the secret to stdout
+    pub command: Option<String>,
+    pub destination: PathBuf,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub mode: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -82,6 +250,8 @@ pub struct ProfileSettings {
     pub path: String,
     #[serde(rename(deserialize = "profilePath"))]
     pub profile_path: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub secrets: Vec<Secret>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
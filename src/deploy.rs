@@ -4,27 +4,42 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 use std::path::Path;
 use thiserror::Error;
 use tokio::{io::AsyncWriteExt, process::Command};
 
+use crate::escalation::Escalation;
+#[cfg(test)]
+use crate::escalation::PrivilegeEscalation;
+use crate::interrupt::{Interrupt, InterruptAction};
+use crate::retry::{RetryPolicy, is_ssh_connection_error};
+use crate::secrets::{ReadSecretError, read_secret};
 use crate::{DeployDataDefsError, DeployDefs, ProfileInfo};
 
 struct ActivateCommandData<'a> {
-    sudo: &'a Option<String>,
+    sudo: &'a Option<Escalation>,
     profile_info: &'a ProfileInfo,
     closure: &'a str,
     auto_rollback: bool,
     temp_path: &'a Path,
     confirm_timeout: u16,
+    confirm_timeout_max: Option<u16>,
+    script_timeout: Option<u16>,
     magic_rollback: bool,
+    boot_check: bool,
     debug_logs: bool,
     log_dir: Option<&'a str>,
     dry_activate: bool,
     boot: bool,
     test: bool,
+    secrets: bool,
     no_emoji: bool,
+    deployer_user: Option<&'a str>,
+    deployer_host: Option<&'a str>,
+    run_id: Option<&'a str>,
+    force_unlock: bool,
+    native_profiles: bool,
 }
 
 fn build_activate_command(data: &ActivateCommandData) -> String {
@@ -65,10 +80,28 @@ fn build_activate_command(data: &ActivateCommandData) -> String {
         self_activate_command, data.confirm_timeout
     );
 
+    if let Some(confirm_timeout_max) = data.confirm_timeout_max {
+        self_activate_command = format!(
+            "{} --confirm-timeout-max {}",
+            self_activate_command, confirm_timeout_max
+        );
+    }
+
+    if let Some(script_timeout) = data.script_timeout {
+        self_activate_command = format!(
+            "{} --script-timeout {}",
+            self_activate_command, script_timeout
+        );
+    }
+
     if data.magic_rollback {
         self_activate_command = format!("{} --magic-rollback", self_activate_command);
     }
 
+    if data.boot_check {
+        self_activate_command = format!("{} --boot-check", self_activate_command);
+    }
+
     if data.auto_rollback {
         self_activate_command = format!("{} --auto-rollback", self_activate_command);
     }
@@ -85,8 +118,38 @@ fn build_activate_command(data: &ActivateCommandData) -> String {
         self_activate_command = format!("{} --test", self_activate_command);
     }
 
-    if let Some(sudo_cmd) = &data.sudo {
-        self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
+    if data.secrets {
+        self_activate_command = format!("{} --secrets", self_activate_command);
+    }
+
+    if let Some(deployer_user) = data.deployer_user {
+        self_activate_command = format!(
+            "{} --deployer-user '{}'",
+            self_activate_command, deployer_user
+        );
+    }
+
+    if let Some(deployer_host) = data.deployer_host {
+        self_activate_command = format!(
+            "{} --deployer-host '{}'",
+            self_activate_command, deployer_host
+        );
+    }
+
+    if let Some(run_id) = data.run_id {
+        self_activate_command = format!("{} --run-id '{}'", self_activate_command, run_id);
+    }
+
+    if data.force_unlock {
+        self_activate_command = format!("{} --force-unlock", self_activate_command);
+    }
+
+    if data.native_profiles {
+        self_activate_command = format!("{} --native-profiles", self_activate_command);
+    }
+
+    if let Some(sudo) = &data.sudo {
+        self_activate_command = sudo.wrap(&self_activate_command);
     }
 
     self_activate_command
@@ -94,7 +157,13 @@ fn build_activate_command(data: &ActivateCommandData) -> String {
 
 #[test]
 fn test_activation_command_builder() {
-    let sudo = Some("sudo -u test".to_string());
+    let sudo = Some(Escalation {
+        backend: PrivilegeEscalation::Sudo,
+        custom_command: None,
+        user: Some("test".to_string()),
+        password_on_stdin: false,
+        preserve_env: false,
+    });
     let profile_info = &ProfileInfo::ProfilePath {
         profile_path: "/blah/profiles/test".to_string(),
     };
@@ -117,21 +186,30 @@ fn test_activation_command_builder() {
             auto_rollback,
             temp_path,
             confirm_timeout,
+            confirm_timeout_max: Some(300),
+            script_timeout: Some(120),
             magic_rollback,
+            boot_check: true,
             debug_logs,
             log_dir,
             dry_activate,
             boot,
             test,
+            secrets: false,
             no_emoji: false,
+            deployer_user: Some("alice"),
+            deployer_host: Some("laptop"),
+            run_id: Some("1700000000-42"),
+            force_unlock: false,
+            native_profiles: false,
         }),
-        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt activate '/nix/store/blah/etc' --profile-path '/blah/profiles/test' --temp-path '/tmp' --confirm-timeout 30 --magic-rollback --auto-rollback"
+        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt activate '/nix/store/blah/etc' --profile-path '/blah/profiles/test' --temp-path '/tmp' --confirm-timeout 30 --confirm-timeout-max 300 --script-timeout 120 --magic-rollback --boot-check --auto-rollback --deployer-user 'alice' --deployer-host 'laptop' --run-id '1700000000-42'"
             .to_string(),
     );
 }
 
 struct WaitCommandData<'a> {
-    sudo: &'a Option<String>,
+    sudo: &'a Option<Escalation>,
     closure: &'a str,
     temp_path: &'a Path,
     activation_timeout: Option<u16>,
@@ -168,8 +246,8 @@ fn build_wait_command(data: &WaitCommandData) -> String {
         );
     }
 
-    if let Some(sudo_cmd) = &data.sudo {
-        self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
+    if let Some(sudo) = &data.sudo {
+        self_activate_command = sudo.wrap(&self_activate_command);
     }
 
     self_activate_command
@@ -177,7 +255,13 @@ fn build_wait_command(data: &WaitCommandData) -> String {
 
 #[test]
 fn test_wait_command_builder() {
-    let sudo = Some("sudo -u test".to_string());
+    let sudo = Some(Escalation {
+        backend: PrivilegeEscalation::Sudo,
+        custom_command: None,
+        user: Some("test".to_string()),
+        password_on_stdin: false,
+        preserve_env: false,
+    });
     let closure = "/nix/store/blah/etc";
     let temp_path = Path::new("/tmp");
     let activation_timeout = Some(600);
@@ -200,12 +284,13 @@ fn test_wait_command_builder() {
 }
 
 struct RevokeCommandData<'a> {
-    sudo: &'a Option<String>,
+    sudo: &'a Option<Escalation>,
     closure: &'a str,
     profile_info: ProfileInfo,
     debug_logs: bool,
     log_dir: Option<&'a str>,
     no_emoji: bool,
+    native_profiles: bool,
 }
 
 fn build_revoke_command(data: &RevokeCommandData) -> String {
@@ -239,8 +324,12 @@ fn build_revoke_command(data: &RevokeCommandData) -> String {
         }
     );
 
-    if let Some(sudo_cmd) = &data.sudo {
-        self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
+    if data.native_profiles {
+        self_activate_command = format!("{} --native-profiles", self_activate_command);
+    }
+
+    if let Some(sudo) = &data.sudo {
+        self_activate_command = sudo.wrap(&self_activate_command);
     }
 
     self_activate_command
@@ -248,7 +337,13 @@ fn build_revoke_command(data: &RevokeCommandData) -> String {
 
 #[test]
 fn test_revoke_command_builder() {
-    let sudo = Some("sudo -u test".to_string());
+    let sudo = Some(Escalation {
+        backend: PrivilegeEscalation::Sudo,
+        custom_command: None,
+        user: Some("test".to_string()),
+        password_on_stdin: false,
+        preserve_env: false,
+    });
     let closure = "/nix/store/blah/etc";
     let profile_info = ProfileInfo::ProfilePath {
         profile_path: "/nix/var/nix/per-user/user/profile".to_string(),
@@ -264,8 +359,349 @@ fn test_revoke_command_builder() {
             debug_logs,
             log_dir,
             no_emoji: false,
+            native_profiles: true,
+        }),
+        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt revoke --profile-path '/nix/var/nix/per-user/user/profile' --native-profiles"
+            .to_string(),
+    );
+}
+
+struct StatusCommandData<'a> {
+    sudo: &'a Option<Escalation>,
+    closure: &'a str,
+    profile_info: ProfileInfo,
+    temp_path: &'a Path,
+    debug_logs: bool,
+    log_dir: Option<&'a str>,
+    no_emoji: bool,
+}
+
+fn build_status_command(data: &StatusCommandData) -> String {
+    let mut self_activate_command = format!("{}/activate-rs", data.closure);
+
+    if data.debug_logs {
+        self_activate_command = format!("{} --debug-logs", self_activate_command);
+    }
+
+    if let Some(log_dir) = data.log_dir {
+        self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
+    }
+
+    if data.no_emoji {
+        self_activate_command = format!("{} --no-emoji", self_activate_command);
+    }
+
+    self_activate_command = format!(
+        "{} status '{}' {} --temp-path '{}'",
+        self_activate_command,
+        data.closure,
+        match &data.profile_info {
+            ProfileInfo::ProfilePath { profile_path } =>
+                format!("--profile-path '{}'", profile_path),
+            ProfileInfo::ProfileUserAndName {
+                profile_user,
+                profile_name,
+            } => format!(
+                "--profile-user {} --profile-name {}",
+                profile_user, profile_name
+            ),
+        },
+        data.temp_path.display()
+    );
+
+    if let Some(sudo) = &data.sudo {
+        self_activate_command = sudo.wrap(&self_activate_command);
+    }
+
+    self_activate_command
+}
+
+#[test]
+fn test_status_command_builder() {
+    let sudo = Some(Escalation {
+        backend: PrivilegeEscalation::Sudo,
+        custom_command: None,
+        user: Some("test".to_string()),
+        password_on_stdin: false,
+        preserve_env: false,
+    });
+    let closure = "/nix/store/blah/etc";
+    let profile_info = ProfileInfo::ProfileUserAndName {
+        profile_user: "test".to_string(),
+        profile_name: "profile".to_string(),
+    };
+
+    assert_eq!(
+        build_status_command(&StatusCommandData {
+            sudo: &sudo,
+            closure,
+            profile_info,
+            temp_path: Path::new("/tmp"),
+            debug_logs: false,
+            log_dir: None,
+            no_emoji: false,
+        }),
+        "sudo -u test /nix/store/blah/etc/activate-rs status '/nix/store/blah/etc' --profile-user test --profile-name profile --temp-path '/tmp'"
+            .to_string(),
+    );
+}
+
+struct StageSecretsCommandData<'a> {
+    sudo: &'a Option<Escalation>,
+    closure: &'a str,
+    temp_path: &'a Path,
+    debug_logs: bool,
+    log_dir: Option<&'a str>,
+    no_emoji: bool,
+}
+
+fn build_stage_secrets_command(data: &StageSecretsCommandData) -> String {
+    let mut self_activate_command = format!("{}/activate-rs", data.closure);
+
+    if data.debug_logs {
+        self_activate_command = format!("{} --debug-logs", self_activate_command);
+    }
+
+    if let Some(log_dir) = data.log_dir {
+        self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
+    }
+
+    if data.no_emoji {
+        self_activate_command = format!("{} --no-emoji", self_activate_command);
+    }
+
+    self_activate_command = format!(
+        "{} stage-secrets '{}' --temp-path '{}'",
+        self_activate_command,
+        data.closure,
+        data.temp_path.display(),
+    );
+
+    if let Some(sudo) = &data.sudo {
+        self_activate_command = sudo.wrap(&self_activate_command);
+    }
+
+    self_activate_command
+}
+
+#[test]
+fn test_stage_secrets_command_builder() {
+    let sudo = Some(Escalation {
+        backend: PrivilegeEscalation::Sudo,
+        custom_command: None,
+        user: Some("test".to_string()),
+        password_on_stdin: false,
+        preserve_env: false,
+    });
+    let closure = "/nix/store/blah/etc";
+    let temp_path = Path::new("/tmp");
+
+    assert_eq!(
+        build_stage_secrets_command(&StageSecretsCommandData {
+            sudo: &sudo,
+            closure,
+            temp_path,
+            debug_logs: false,
+            log_dir: None,
+            no_emoji: true,
+        }),
+        "sudo -u test /nix/store/blah/etc/activate-rs --no-emoji stage-secrets '/nix/store/blah/etc' --temp-path '/tmp'"
+            .to_string(),
+    );
+}
+
+struct StateCommandData<'a> {
+    sudo: &'a Option<Escalation>,
+    closure: &'a str,
+    profile_info: ProfileInfo,
+}
+
+/// Exit code of the state command when the closure, and with it its `activate-rs`, is missing
+const STATE_NOT_PUSHED_EXIT_CODE: i32 = 127;
+
+fn build_state_command(data: &StateCommandData) -> String {
+    let mut self_state_command = format!(
+        "{}/activate-rs state {}",
+        data.closure,
+        match &data.profile_info {
+            ProfileInfo::ProfilePath { profile_path } =>
+                format!("--profile-path '{}'", profile_path),
+            ProfileInfo::ProfileUserAndName {
+                profile_user,
+                profile_name,
+            } => format!(
+                "--profile-user {} --profile-name {}",
+                profile_user, profile_name
+            ),
+        }
+    );
+
+    if let Some(sudo) = &data.sudo {
+        self_state_command = sudo.wrap(&self_state_command);
+    }
+
+    // the closure may not be on the node yet, which is told apart from activate-rs failing
+    format!(
+        "[ -x '{}/activate-rs' ] || exit {}; {}",
+        data.closure, STATE_NOT_PUSHED_EXIT_CODE, self_state_command
+    )
+}
+
+#[test]
+fn test_state_command_builder() {
+    let sudo = Some(Escalation {
+        backend: PrivilegeEscalation::Sudo,
+        custom_command: None,
+        user: Some("test".to_string()),
+        password_on_stdin: false,
+        preserve_env: false,
+    });
+
+    assert_eq!(
+        build_state_command(&StateCommandData {
+            sudo: &sudo,
+            closure: "/nix/store/blah/etc",
+            profile_info: ProfileInfo::ProfileUserAndName {
+                profile_user: "test".to_string(),
+                profile_name: "home".to_string(),
+            },
+        }),
+        "[ -x '/nix/store/blah/etc/activate-rs' ] || exit 127; sudo -u test /nix/store/blah/etc/activate-rs state --profile-user test --profile-name home"
+            .to_string(),
+    );
+    assert_eq!(
+        build_state_command(&StateCommandData {
+            sudo: &None,
+            closure: "/nix/store/blah/etc",
+            profile_info: ProfileInfo::ProfilePath {
+                profile_path: "/nix/var/nix/profiles/system".to_string(),
+            },
+        }),
+        "[ -x '/nix/store/blah/etc/activate-rs' ] || exit 127; /nix/store/blah/etc/activate-rs state --profile-path '/nix/var/nix/profiles/system'"
+            .to_string(),
+    );
+}
+
+struct ConfirmCommandData<'a> {
+    sudo: &'a Option<Escalation>,
+    closure: &'a str,
+    temp_path: &'a Path,
+    debug_logs: bool,
+    log_dir: Option<&'a str>,
+    no_emoji: bool,
+}
+
+fn build_confirm_command(data: &ConfirmCommandData) -> String {
+    let mut self_activate_command = format!("{}/activate-rs", data.closure);
+
+    if data.debug_logs {
+        self_activate_command = format!("{} --debug-logs", self_activate_command);
+    }
+
+    if let Some(log_dir) = data.log_dir {
+        self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
+    }
+
+    if data.no_emoji {
+        self_activate_command = format!("{} --no-emoji", self_activate_command);
+    }
+
+    self_activate_command = format!(
+        "{} confirm '{}' --temp-path '{}'",
+        self_activate_command,
+        data.closure,
+        data.temp_path.display(),
+    );
+
+    if let Some(sudo) = &data.sudo {
+        self_activate_command = sudo.wrap(&self_activate_command);
+    }
+
+    self_activate_command
+}
+
+#[test]
+fn test_confirm_command_builder() {
+    let sudo = Some(Escalation {
+        backend: PrivilegeEscalation::Sudo,
+        custom_command: None,
+        user: Some("test".to_string()),
+        password_on_stdin: false,
+        preserve_env: false,
+    });
+
+    assert_eq!(
+        build_confirm_command(&ConfirmCommandData {
+            sudo: &sudo,
+            closure: "/nix/store/blah/etc",
+            temp_path: Path::new("/tmp"),
+            debug_logs: false,
+            log_dir: Some("/tmp/logs"),
+            no_emoji: false,
         }),
-        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt revoke --profile-path '/nix/var/nix/per-user/user/profile'"
+        "sudo -u test /nix/store/blah/etc/activate-rs --log-dir /tmp/logs confirm '/nix/store/blah/etc' --temp-path '/tmp'"
+            .to_string(),
+    );
+}
+
+struct RejectCommandData<'a> {
+    sudo: &'a Option<Escalation>,
+    closure: &'a str,
+    temp_path: &'a Path,
+    debug_logs: bool,
+    log_dir: Option<&'a str>,
+    no_emoji: bool,
+}
+
+fn build_reject_command(data: &RejectCommandData) -> String {
+    let mut self_activate_command = format!("{}/activate-rs", data.closure);
+
+    if data.debug_logs {
+        self_activate_command = format!("{} --debug-logs", self_activate_command);
+    }
+
+    if let Some(log_dir) = data.log_dir {
+        self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
+    }
+
+    if data.no_emoji {
+        self_activate_command = format!("{} --no-emoji", self_activate_command);
+    }
+
+    self_activate_command = format!(
+        "{} reject '{}' --temp-path '{}'",
+        self_activate_command,
+        data.closure,
+        data.temp_path.display(),
+    );
+
+    if let Some(sudo) = &data.sudo {
+        self_activate_command = sudo.wrap(&self_activate_command);
+    }
+
+    self_activate_command
+}
+
+#[test]
+fn test_reject_command_builder() {
+    let sudo = Some(Escalation {
+        backend: PrivilegeEscalation::Sudo,
+        custom_command: None,
+        user: Some("test".to_string()),
+        password_on_stdin: false,
+        preserve_env: false,
+    });
+
+    assert_eq!(
+        build_reject_command(&RejectCommandData {
+            sudo: &sudo,
+            closure: "/nix/store/blah/etc",
+            temp_path: Path::new("/tmp"),
+            debug_logs: true,
+            log_dir: None,
+            no_emoji: false,
+        }),
+        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs reject '/nix/store/blah/etc' --temp-path '/tmp'"
             .to_string(),
     );
 }
@@ -287,78 +723,536 @@ async fn handle_sudo_stdin(
                 .await;
             Ok(())
         }
-        None => Err(std::io::Error::other(
-            "Failed to open stdin for sudo command",
-        )),
+        None => Err(std::io::Error::other(
+            "Failed to open stdin for sudo command",
+        )),
+    }
+}
+
+/// Sends the confirmation token, which the remote side reads after the sudo password
+async fn pipe_token(child: &mut tokio::process::Child, token: &str) -> Result<(), std::io::Error> {
+    // dropping stdin closes it, as nothing else is sent
+    match child.stdin.take() {
+        Some(mut stdin) => stdin.write_all(format!("{}\n", token).as_bytes()).await,
+        None => Err(std::io::Error::other(
+            "Failed to open stdin for the confirmation token",
+        )),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfirmProfileError {
+    #[error("Failed to run confirmation command over SSH (the server should roll back): {0}")]
+    SSHConfirm(std::io::Error),
+    #[error(
+        "Confirming activation over SSH resulted in a bad exit code (the server should roll back): {0:?}"
+    )]
+    SSHConfirmExit(Option<i32>),
+}
+
+pub async fn confirm_profile(
+    deploy_data: &super::DeployData,
+    deploy_defs: &super::DeployDefs,
+    temp_path: &Path,
+    ssh_addr: &str,
+    token: &str,
+) -> Result<(), ConfirmProfileError> {
+    let confirm_command = build_confirm_command(&ConfirmCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: &deploy_data.profile.profile_settings.path,
+        temp_path,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir.as_deref(),
+        no_emoji: deploy_data.no_emoji,
+    });
+
+    debug!(
+        "Attempting to run command to confirm deployment: {}",
+        confirm_command
+    );
+
+    // only connection errors are retried, a failure of the command itself means that the activation
+    // isn't waiting anymore
+    let attempts = std::cell::Cell::new(0u32);
+    let ssh_confirm_exit_status = RetryPolicy::from_settings(&deploy_data.merged_settings)
+        .run(
+            "Confirming the deployment",
+            |res: &std::io::Result<std::process::ExitStatus>| matches!(res, Ok(status) if is_ssh_connection_error(status.code())),
+            || async {
+                attempts.set(attempts.get() + 1);
+
+                let mut ssh_confirm_command = Command::new("ssh");
+                ssh_confirm_command
+                    .arg(ssh_addr)
+                    .stdin(std::process::Stdio::piped());
+
+                for ssh_opt in &deploy_data.merged_settings.ssh_opts {
+                    ssh_confirm_command.arg(ssh_opt);
+                }
+
+                let mut ssh_confirm_child = ssh_confirm_command.arg(&confirm_command).spawn()?;
+
+                if deploy_data
+                    .merged_settings
+                    .interactive_sudo
+                    .unwrap_or(false)
+                {
+                    trace!("[confirm] Piping in sudo password");
+                    handle_sudo_stdin(&mut ssh_confirm_child, deploy_defs).await?;
+                }
+                pipe_token(&mut ssh_confirm_child, token).await?;
+
+                ssh_confirm_child.wait().await
+            },
+        )
+        .await
+        .map_err(ConfirmProfileError::SSHConfirm)?;
+
+    match ssh_confirm_exit_status.code() {
+        Some(0) => (),
+        // an earlier attempt may have reached the activation before its connection dropped, in which
+        // case the activation stopped waiting because it was confirmed
+        a if attempts.get() > 1 => {
+            warn!(
+                "Confirming failed after a retry, checking whether an earlier attempt went through"
+            );
+            match is_deployed(deploy_data, deploy_defs).await {
+                Ok(true) => (),
+                Ok(false) => return Err(ConfirmProfileError::SSHConfirmExit(a)),
+                Err(e) => {
+                    warn!("Failed to check the profile status: {}", e);
+                    return Err(ConfirmProfileError::SSHConfirmExit(a));
+                }
+            }
+        }
+        a => return Err(ConfirmProfileError::SSHConfirmExit(a)),
+    };
+
+    info!("Deployment confirmed.");
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RejectActivationError {
+    #[error("Failed to run rejection command over SSH: {0}")]
+    SSHReject(std::io::Error),
+    #[error("Rejecting the activation over SSH resulted in a bad exit code: {0:?}")]
+    SSHRejectExit(Option<i32>),
+}
+
+/// Makes an activation waiting for confirmation roll back right away, rather than once
+/// `confirmTimeout` runs out
+pub async fn reject_activation(
+    deploy_data: &super::DeployData,
+    deploy_defs: &super::DeployDefs,
+    temp_path: &Path,
+    ssh_addr: &str,
+    token: &str,
+) -> Result<(), RejectActivationError> {
+    let reject_command = build_reject_command(&RejectCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: &deploy_data.profile.profile_settings.path,
+        temp_path,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir.as_deref(),
+        no_emoji: deploy_data.no_emoji,
+    });
+
+    debug!("Constructed reject command: {}", reject_command);
+
+    let ssh_reject_exit_status = RetryPolicy::from_settings(&deploy_data.merged_settings)
+        .run(
+            "Rejecting the activation",
+            |res: &std::io::Result<std::process::ExitStatus>| matches!(res, Ok(status) if is_ssh_connection_error(status.code())),
+            || async {
+                let mut ssh_reject_command = Command::new("ssh");
+                ssh_reject_command
+                    .arg(ssh_addr)
+                    .stdin(std::process::Stdio::piped());
+
+                for ssh_opt in &deploy_data.merged_settings.ssh_opts {
+                    ssh_reject_command.arg(ssh_opt);
+                }
+
+                let mut ssh_reject_child = ssh_reject_command.arg(&reject_command).spawn()?;
+
+                if deploy_data
+                    .merged_settings
+                    .interactive_sudo
+                    .unwrap_or(false)
+                {
+                    trace!("[reject] Piping in sudo password");
+                    handle_sudo_stdin(&mut ssh_reject_child, deploy_defs).await?;
+                }
+                pipe_token(&mut ssh_reject_child, token).await?;
+
+                ssh_reject_child.wait().await
+            },
+        )
+        .await
+        .map_err(RejectActivationError::SSHReject)?;
+
+    match ssh_reject_exit_status.code() {
+        Some(0) => (),
+        a => return Err(RejectActivationError::SSHRejectExit(a)),
+    };
+
+    info!("Activation rejected, the node is rolling back.");
+
+    Ok(())
+}
+
+struct ExtendCommandData<'a> {
+    sudo: &'a Option<Escalation>,
+    closure: &'a str,
+    temp_path: &'a Path,
+    seconds: u16,
+    debug_logs: bool,
+    log_dir: Option<&'a str>,
+    no_emoji: bool,
+}
+
+fn build_extend_command(data: &ExtendCommandData) -> String {
+    let mut self_activate_command = format!("{}/activate-rs", data.closure);
+
+    if data.debug_logs {
+        self_activate_command = format!("{} --debug-logs", self_activate_command);
+    }
+
+    if let Some(log_dir) = data.log_dir {
+        self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
+    }
+
+    if data.no_emoji {
+        self_activate_command = format!("{} --no-emoji", self_activate_command);
+    }
+
+    self_activate_command = format!(
+        "{} extend '{}' --temp-path '{}' --seconds {}",
+        self_activate_command,
+        data.closure,
+        data.temp_path.display(),
+        data.seconds,
+    );
+
+    if let Some(sudo) = &data.sudo {
+        self_activate_command = sudo.wrap(&self_activate_command);
+    }
+
+    self_activate_command
+}
+
+#[test]
+fn test_extend_command_builder() {
+    let sudo = Some(Escalation {
+        backend: PrivilegeEscalation::Sudo,
+        custom_command: None,
+        user: Some("test".to_string()),
+        password_on_stdin: false,
+        preserve_env: false,
+    });
+
+    assert_eq!(
+        build_extend_command(&ExtendCommandData {
+            sudo: &sudo,
+            closure: "/nix/store/blah/etc",
+            temp_path: Path::new("/tmp"),
+            seconds: 30,
+            debug_logs: false,
+            log_dir: None,
+            no_emoji: true,
+        }),
+        "sudo -u test /nix/store/blah/etc/activate-rs --no-emoji extend '/nix/store/blah/etc' --temp-path '/tmp' --seconds 30"
+            .to_string(),
+    );
+}
+
+#[derive(Error, Debug)]
+pub enum ExtendActivationError {
+    #[error("Failed to run heartbeat command over SSH: {0}")]
+    SSHExtend(std::io::Error),
+    #[error("Sending a heartbeat over SSH resulted in a bad exit code: {0:?}")]
+    SSHExtendExit(Option<i32>),
+}
+
+/// Keeps an activation waiting for confirmation for another `seconds`, within `confirmTimeoutMax`
+pub async fn extend_activation(
+    deploy_data: &super::DeployData,
+    deploy_defs: &super::DeployDefs,
+    temp_path: &Path,
+    ssh_addr: &str,
+    token: &str,
+    seconds: u16,
+) -> Result<(), ExtendActivationError> {
+    let extend_command = build_extend_command(&ExtendCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: &deploy_data.profile.profile_settings.path,
+        temp_path,
+        seconds,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir.as_deref(),
+        no_emoji: deploy_data.no_emoji,
+    });
+
+    debug!("Constructed heartbeat command: {}", extend_command);
+
+    // not retried, the next heartbeat is
+    let mut ssh_extend_command = Command::new("ssh");
+    ssh_extend_command
+        .arg(ssh_addr)
+        .stdin(std::process::Stdio::piped());
+
+    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
+        ssh_extend_command.arg(ssh_opt);
+    }
+
+    let mut ssh_extend_child = ssh_extend_command
+        .arg(&extend_command)
+        .spawn()
+        .map_err(ExtendActivationError::SSHExtend)?;
+
+    if deploy_data
+        .merged_settings
+        .interactive_sudo
+        .unwrap_or(false)
+    {
+        trace!("[extend] Piping in sudo password");
+        handle_sudo_stdin(&mut ssh_extend_child, deploy_defs)
+            .await
+            .map_err(ExtendActivationError::SSHExtend)?;
+    }
+    pipe_token(&mut ssh_extend_child, token)
+        .await
+        .map_err(ExtendActivationError::SSHExtend)?;
+
+    let ssh_extend_exit_status = ssh_extend_child
+        .wait()
+        .await
+        .map_err(ExtendActivationError::SSHExtend)?;
+
+    match ssh_extend_exit_status.code() {
+        Some(0) => Ok(()),
+        a => Err(ExtendActivationError::SSHExtendExit(a)),
+    }
+}
+
+/// Sends heartbeats extending the confirmation window by `confirm_timeout` until dropped. Once they
+/// stop, the activation rolls back after at most `confirm_timeout`.
+async fn send_heartbeats(
+    deploy_data: &super::DeployData,
+    deploy_defs: &super::DeployDefs,
+    temp_path: &Path,
+    ssh_addr: &str,
+    token: &str,
+    confirm_timeout: u16,
+) {
+    // a few heartbeats per window, so that a single slow or failed one doesn't end it
+    let interval = std::time::Duration::from_secs((confirm_timeout / 3).max(1) as u64);
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        match extend_activation(
+            deploy_data,
+            deploy_defs,
+            temp_path,
+            ssh_addr,
+            token,
+            confirm_timeout,
+        )
+        .await
+        {
+            Ok(()) => debug!("Extended the confirmation window by {}s", confirm_timeout),
+            Err(e) => warn!(
+                "Failed to extend the confirmation window of node `{}`: {}",
+                deploy_data.node_name, e
+            ),
+        }
     }
 }
 
 #[derive(Error, Debug)]
-pub enum ConfirmProfileError {
-    #[error("Failed to run confirmation command over SSH (the server should roll back): {0}")]
-    SSHConfirm(std::io::Error),
-    #[error(
-        "Confirming activation over SSH resulted in a bad exit code (the server should roll back): {0:?}"
-    )]
-    SSHConfirmExit(Option<i32>),
+pub enum UploadSecretsError {
+    #[error("Failed to read secret: {0}")]
+    Read(#[from] ReadSecretError),
+    #[error("Failed to encode secrets: {0}")]
+    Encode(serde_json::Error),
+    #[error("Failed to spawn secrets upload command over SSH: {0}")]
+    SSHSpawn(std::io::Error),
+    #[error("Failed to pipe secrets to SSH: {0}")]
+    SSHPipe(std::io::Error),
+    #[error("Failed to run secrets upload command over SSH: {0}")]
+    SSH(std::io::Error),
+    #[error("Uploading secrets over SSH resulted in a bad exit code: {0:?}")]
+    SSHExit(Option<i32>),
 }
 
-pub async fn confirm_profile(
+/// Sends the secrets of a profile to the node, where they are staged until activation
+pub async fn upload_secrets(
     deploy_data: &super::DeployData,
     deploy_defs: &super::DeployDefs,
     temp_path: &Path,
     ssh_addr: &str,
-) -> Result<(), ConfirmProfileError> {
-    let mut ssh_confirm_command = Command::new("ssh");
-    ssh_confirm_command
-        .arg(ssh_addr)
-        .stdin(std::process::Stdio::piped());
+) -> Result<(), UploadSecretsError> {
+    info!(
+        "Uploading secrets for profile `{}` to node `{}`",
+        deploy_data.profile_name, deploy_data.node_name
+    );
 
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_confirm_command.arg(ssh_opt);
+    let mut secrets = Vec::new();
+    for secret in &deploy_data.profile.profile_settings.secrets {
+        secrets.push(read_secret(secret).await?);
     }
+    let payload = serde_json::to_vec(&secrets).map_err(UploadSecretsError::Encode)?;
+
+    let self_stage_command = build_stage_secrets_command(&StageSecretsCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: &deploy_data.profile.profile_settings.path,
+        temp_path,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir.as_deref(),
+        no_emoji: deploy_data.no_emoji,
+    });
 
-    let lock_path = super::make_lock_path(temp_path, &deploy_data.profile.profile_settings.path);
+    debug!("Constructed stage-secrets command: {}", self_stage_command);
 
-    let mut confirm_command = format!("rm {}", lock_path.display());
-    if let Some(sudo_cmd) = &deploy_defs.sudo {
-        confirm_command = format!("{} {}", sudo_cmd, confirm_command);
-    }
+    let mut ssh_stage_command = Command::new("ssh");
+    ssh_stage_command
+        .arg(ssh_addr)
+        .stdin(std::process::Stdio::piped());
 
-    debug!(
-        "Attempting to run command to confirm deployment: {}",
-        confirm_command
-    );
+    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
+        ssh_stage_command.arg(ssh_opt);
+    }
 
-    let mut ssh_confirm_child = ssh_confirm_command
-        .arg(confirm_command)
+    let mut ssh_stage_child = ssh_stage_command
+        .arg(self_stage_command)
         .spawn()
-        .map_err(ConfirmProfileError::SSHConfirm)?;
+        .map_err(UploadSecretsError::SSHSpawn)?;
 
     if deploy_data
         .merged_settings
         .interactive_sudo
         .unwrap_or(false)
     {
-        trace!("[confirm] Piping in sudo password");
-        handle_sudo_stdin(&mut ssh_confirm_child, deploy_defs)
+        trace!("[stage-secrets] Piping in sudo password");
+        handle_sudo_stdin(&mut ssh_stage_child, deploy_defs)
+            .await
+            .map_err(UploadSecretsError::SSHPipe)?;
+    }
+
+    // Dropping stdin closes it, letting the remote side know that all secrets were sent
+    match ssh_stage_child.stdin.take() {
+        Some(mut stdin) => stdin
+            .write_all(&payload)
             .await
-            .map_err(ConfirmProfileError::SSHConfirm)?;
+            .map_err(UploadSecretsError::SSHPipe)?,
+        None => {
+            return Err(UploadSecretsError::SSHPipe(std::io::Error::other(
+                "Failed to open stdin for secrets upload",
+            )));
+        }
     }
 
-    let ssh_confirm_exit_status = ssh_confirm_child
+    let ssh_stage_exit_status = ssh_stage_child
         .wait()
         .await
-        .map_err(ConfirmProfileError::SSHConfirm)?;
+        .map_err(UploadSecretsError::SSH)?;
 
-    match ssh_confirm_exit_status.code() {
-        Some(0) => (),
-        a => return Err(ConfirmProfileError::SSHConfirmExit(a)),
-    };
+    match ssh_stage_exit_status.code() {
+        Some(0) => Ok(()),
+        a => Err(UploadSecretsError::SSHExit(a)),
+    }
+}
 
-    info!("Deployment confirmed.");
+#[derive(Error, Debug)]
+pub enum SelectHostnameError {
+    #[error("Node {0} has neither `hostname` nor `hostnames` set")]
+    NoHostname(String),
+    #[error("None of the addresses of node {0} are reachable: {1}")]
+    Unreachable(String, String),
+}
 
-    Ok(())
+/// Whether the node accepts ssh connections at its current address
+pub async fn probe_ssh(deploy_data: &super::DeployData, ssh_user: &str) -> bool {
+    let mut ssh_probe_command = Command::new("ssh");
+    ssh_probe_command
+        .arg(deploy_data.ssh_addr(ssh_user))
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null());
+
+    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
+        ssh_probe_command.arg(ssh_opt);
+    }
+
+    // without a terminal to prompt on, ssh would hang asking for a password or host key
+    match ssh_probe_command
+        .arg("-o")
+        .arg("BatchMode=yes")
+        .arg("-o")
+        .arg("ConnectTimeout=10")
+        .arg("true")
+        .status()
+        .await
+    {
+        Ok(status) => {
+            debug!(
+                "ssh probe of {} exited with {:?}",
+                deploy_data.hostname(),
+                status.code()
+            );
+            status.success()
+        }
+        Err(e) => {
+            debug!("Failed to spawn ssh probe: {}", e);
+            false
+        }
+    }
+}
+
+/// Points the node at the first of its addresses that is reachable. Addresses are only probed
+/// when there is more than one to choose from.
+pub async fn select_hostname(
+    deploy_data: &mut super::DeployData,
+    ssh_user: &str,
+) -> Result<(), SelectHostnameError> {
+    let candidates = deploy_data.hostname_candidates();
+
+    if candidates.len() <= 1 {
+        return match candidates.into_iter().next() {
+            Some(hostname) => {
+                deploy_data.node.node_settings.hostname = hostname;
+                Ok(())
+            }
+            None => Err(SelectHostnameError::NoHostname(
+                deploy_data.node_name.clone(),
+            )),
+        };
+    }
+
+    for hostname in &candidates {
+        deploy_data.node.node_settings.hostname = hostname.clone();
+
+        if probe_ssh(deploy_data, ssh_user).await {
+            info!(
+                "Using address `{}` for node `{}`",
+                hostname, deploy_data.node_name
+            );
+            return Ok(());
+        }
+
+        warn!(
+            "Address `{}` of node `{}` is unreachable",
+            hostname, deploy_data.node_name
+        );
+    }
+
+    Err(SelectHostnameError::Unreachable(
+        deploy_data.node_name.clone(),
+        candidates.join(", "),
+    ))
 }
 
 #[derive(Error, Debug)]
@@ -381,18 +1275,189 @@ pub enum DeployProfileError {
     #[error("Failed to pipe to child stdin: {0}")]
     SSHActivatePipe(std::io::Error),
 
+    #[error("Failed to run reboot command over SSH: {0}")]
+    SSHReboot(std::io::Error),
+    #[error("Rebooting over SSH resulted in a bad exit code: {0:?}")]
+    SSHRebootExit(Option<i32>),
+
     #[error("Error confirming deployment: {0}")]
     Confirm(#[from] ConfirmProfileError),
+    #[error("Error rejecting the activation after an interrupt: {0}")]
+    Reject(#[from] RejectActivationError),
+    #[error("Deployment was interrupted, the activation was rolled back")]
+    Rejected,
+    #[error(
+        "Profile {0} of node {1} is locked by another deployment (see above), use --force-unlock if that deployment is gone"
+    )]
+    Locked(String, String),
+    #[error(
+        "The activation script of profile {0} on node {1} didn't finish within scriptTimeout, it was killed and the profile rolled back"
+    )]
+    ScriptTimeout(String, String),
+    #[error("Error uploading secrets: {0}")]
+    Secrets(#[from] UploadSecretsError),
     #[error("Deployment data invalid: {0}")]
     InvalidDeployDataDefs(#[from] DeployDataDefsError),
+    #[error("{0}")]
+    Token(#[from] crate::confirmation::ConfirmationError),
+}
+
+async fn wait_for_activation(
+    deploy_data: &super::DeployData,
+    deploy_defs: &super::DeployDefs,
+    ssh_addr: &str,
+    wait_command: &str,
+) -> Result<(), DeployProfileError> {
+    let mut ssh_wait_command = Command::new("ssh");
+    ssh_wait_command
+        .arg(ssh_addr)
+        .stdin(std::process::Stdio::piped());
+
+    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
+        ssh_wait_command.arg(ssh_opt);
+    }
+
+    let mut ssh_wait_child = ssh_wait_command
+        .arg(wait_command)
+        .spawn()
+        .map_err(DeployProfileError::SSHWait)?;
+
+    if deploy_data
+        .merged_settings
+        .interactive_sudo
+        .unwrap_or(false)
+    {
+        trace!("[wait] Piping in sudo password");
+        handle_sudo_stdin(&mut ssh_wait_child, deploy_defs)
+            .await
+            .map_err(DeployProfileError::SSHActivatePipe)?;
+    }
+
+    match ssh_wait_child
+        .wait()
+        .await
+        .map_err(DeployProfileError::SSHWait)?
+        .code()
+    {
+        Some(0) => Ok(()),
+        a => Err(DeployProfileError::SSHWaitExit(a)),
+    }
+}
+
+/// How long to wait before trying to reach a rebooting node again
+const BOOT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Waits for the boot-time check of a node that is rebooting into the new generation to listen for
+/// confirmation, trying to reach the node again until `activationTimeout` runs out
+async fn wait_for_boot_check(
+    deploy_data: &super::DeployData,
+    deploy_defs: &super::DeployDefs,
+    ssh_addr: &str,
+    wait_command: &str,
+) -> Result<(), DeployProfileError> {
+    let deadline = tokio::time::Instant::now()
+        + std::time::Duration::from_secs(
+            deploy_data
+                .merged_settings
+                .activation_timeout
+                .unwrap_or(240) as u64,
+        );
+
+    loop {
+        match wait_for_activation(deploy_data, deploy_defs, ssh_addr, wait_command).await {
+            Err(DeployProfileError::SSHWaitExit(a))
+                if is_ssh_connection_error(a) && tokio::time::Instant::now() < deadline =>
+            {
+                debug!(
+                    "Node `{}` is not reachable yet, trying again",
+                    deploy_data.node_name
+                );
+                tokio::time::sleep(BOOT_POLL_INTERVAL).await;
+            }
+            x => return x,
+        }
+    }
+}
+
+/// Reboots the node, the connection dropping on the way is expected
+async fn reboot_node(
+    deploy_data: &super::DeployData,
+    deploy_defs: &super::DeployDefs,
+    ssh_addr: &str,
+) -> Result<(), DeployProfileError> {
+    let reboot_command = match &deploy_defs.sudo {
+        Some(sudo) => sudo.wrap("reboot"),
+        None => "reboot".to_string(),
+    };
+
+    debug!("Constructed reboot command: {}", reboot_command);
+
+    let mut ssh_reboot_command = Command::new("ssh");
+    ssh_reboot_command
+        .arg(ssh_addr)
+        .stdin(std::process::Stdio::piped());
+
+    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
+        ssh_reboot_command.arg(ssh_opt);
+    }
+
+    let mut ssh_reboot_child = ssh_reboot_command
+        .arg(reboot_command)
+        .spawn()
+        .map_err(DeployProfileError::SSHReboot)?;
+
+    if deploy_data
+        .merged_settings
+        .interactive_sudo
+        .unwrap_or(false)
+    {
+        trace!("[reboot] Piping in sudo password");
+        handle_sudo_stdin(&mut ssh_reboot_child, deploy_defs)
+            .await
+            .map_err(DeployProfileError::SSHActivatePipe)?;
+    }
+
+    match ssh_reboot_child
+        .wait()
+        .await
+        .map_err(DeployProfileError::SSHReboot)?
+        .code()
+    {
+        Some(0) => Ok(()),
+        a if is_ssh_connection_error(a) => Ok(()),
+        a => Err(DeployProfileError::SSHRebootExit(a)),
+    }
+}
+
+/// Exit code of `activate-rs activate` when another deployment holds the profile lock
+pub const LOCKED_EXIT_CODE: i32 = 3;
+
+/// Exit code of `activate-rs activate` when the activation script was killed after `scriptTimeout`
+pub const SCRIPT_TIMEOUT_EXIT_CODE: i32 = 4;
+
+fn activate_exit_error(deploy_data: &super::DeployData, code: Option<i32>) -> DeployProfileError {
+    match code {
+        Some(SCRIPT_TIMEOUT_EXIT_CODE) => DeployProfileError::ScriptTimeout(
+            deploy_data.profile_name.clone(),
+            deploy_data.node_name.clone(),
+        ),
+        Some(LOCKED_EXIT_CODE) => DeployProfileError::Locked(
+            deploy_data.profile_name.clone(),
+            deploy_data.node_name.clone(),
+        ),
+        a => DeployProfileError::SSHActivateExit(a),
+    }
 }
 
+/// Activates the profile. Once `interrupt` is set, an activation waiting for confirmation is
+/// confirmed or rejected according to `interrupt.action`.
 pub async fn deploy_profile(
     deploy_data: &super::DeployData,
     deploy_defs: &super::DeployDefs,
     dry_activate: bool,
     boot: bool,
     test: bool,
+    interrupt: &Interrupt,
 ) -> Result<(), DeployProfileError> {
     if !dry_activate {
         info!(
@@ -414,6 +1479,13 @@ pub async fn deploy_profile(
 
     let auto_rollback = deploy_data.merged_settings.auto_rollback.unwrap_or(true);
 
+    let boot_check =
+        magic_rollback && !dry_activate && deploy_data.merged_settings.boot_check.unwrap_or(false);
+
+    let secrets = !dry_activate && !deploy_data.profile.profile_settings.secrets.is_empty();
+
+    let (deployer_user, deployer_host) = crate::lock::deployer();
+
     let self_activate_command = build_activate_command(&ActivateCommandData {
         sudo: &deploy_defs.sudo,
         profile_info: &deploy_data.get_profile_info()?,
@@ -421,23 +1493,31 @@ pub async fn deploy_profile(
         auto_rollback,
         temp_path,
         confirm_timeout,
+        confirm_timeout_max: deploy_data.merged_settings.confirm_timeout_max,
+        script_timeout: deploy_data.merged_settings.script_timeout,
         magic_rollback,
+        boot_check,
         debug_logs: deploy_data.debug_logs,
         log_dir: deploy_data.log_dir.as_deref(),
         dry_activate,
         boot,
         test,
+        secrets,
         no_emoji: deploy_data.no_emoji,
+        deployer_user: Some(&deployer_user),
+        deployer_host: Some(&deployer_host),
+        run_id: deploy_data.run_id.as_deref(),
+        force_unlock: deploy_data.cmd_overrides.force_unlock,
+        native_profiles: deploy_data.merged_settings.native_profiles.unwrap_or(false),
     });
 
     debug!("Constructed activation command: {}", self_activate_command);
 
-    let hostname = match deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &deploy_data.node.node_settings.hostname,
-    };
+    let ssh_addr = deploy_data.ssh_addr(&deploy_defs.ssh_user);
 
-    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+    if secrets {
+        upload_secrets(deploy_data, deploy_defs, temp_path, &ssh_addr).await?;
+    }
 
     let mut ssh_activate_command = Command::new("ssh");
     ssh_activate_command
@@ -448,7 +1528,26 @@ pub async fn deploy_profile(
         ssh_activate_command.arg(ssh_opt);
     }
 
+    let self_wait_command = build_wait_command(&WaitCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: &deploy_data.profile.profile_settings.path,
+        temp_path,
+        activation_timeout,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir.as_deref(),
+        no_emoji: deploy_data.no_emoji,
+    });
+
+    debug!("Constructed wait command: {}", self_wait_command);
+
     if !magic_rollback || dry_activate || boot {
+        // a `--boot` activation with a boot-time check is confirmed after rebooting into it
+        let boot_token = if boot && boot_check {
+            Some(crate::confirmation::generate_token()?)
+        } else {
+            None
+        };
+
         let mut ssh_activate_child = ssh_activate_command
             .arg(self_activate_command)
             .spawn()
@@ -465,6 +1564,12 @@ pub async fn deploy_profile(
                 .map_err(DeployProfileError::SSHActivatePipe)?;
         }
 
+        if let Some(token) = &boot_token {
+            pipe_token(&mut ssh_activate_child, token)
+                .await
+                .map_err(DeployProfileError::SSHActivatePipe)?;
+        }
+
         let ssh_activate_exit_status = ssh_activate_child
             .wait()
             .await
@@ -472,28 +1577,37 @@ pub async fn deploy_profile(
 
         match ssh_activate_exit_status.code() {
             Some(0) => (),
-            a => return Err(DeployProfileError::SSHActivateExit(a)),
+            a => {
+                if interrupt.is_interrupted() {
+                    warn!(
+                        "Only the connection was interrupted, the activation of profile `{}` keeps running on node `{}`",
+                        deploy_data.profile_name, deploy_data.node_name
+                    );
+                }
+                return Err(activate_exit_error(deploy_data, a));
+            }
         };
 
         if dry_activate {
             info!("Completed dry-activate!");
+        } else if let Some(token) = &boot_token {
+            info!(
+                "Rebooting node `{}` into the new generation",
+                deploy_data.node_name
+            );
+            reboot_node(deploy_data, deploy_defs, &ssh_addr).await?;
+            wait_for_boot_check(deploy_data, deploy_defs, &ssh_addr, &self_wait_command).await?;
+
+            info!("Node rebooted into the new generation, attempting to confirm it");
+            confirm_profile(deploy_data, deploy_defs, temp_path, &ssh_addr, token).await?;
         } else if boot {
             info!("Success activating for next boot, done!");
         } else {
             info!("Success activating, done!");
         }
     } else {
-        let self_wait_command = build_wait_command(&WaitCommandData {
-            sudo: &deploy_defs.sudo,
-            closure: &deploy_data.profile.profile_settings.path,
-            temp_path,
-            activation_timeout,
-            debug_logs: deploy_data.debug_logs,
-            log_dir: deploy_data.log_dir.as_deref(),
-            no_emoji: deploy_data.no_emoji,
-        });
-
-        debug!("Constructed wait command: {}", self_wait_command);
+        // only whoever knows it can confirm the activation, other users of the node can't
+        let token = crate::confirmation::generate_token()?;
 
         let mut ssh_activate_child = ssh_activate_command
             .arg(self_activate_command)
@@ -511,16 +1625,11 @@ pub async fn deploy_profile(
                 .map_err(DeployProfileError::SSHActivatePipe)?;
         }
 
-        info!("Creating activation waiter");
-
-        let mut ssh_wait_command = Command::new("ssh");
-        ssh_wait_command
-            .arg(&ssh_addr)
-            .stdin(std::process::Stdio::piped());
+        pipe_token(&mut ssh_activate_child, &token)
+            .await
+            .map_err(DeployProfileError::SSHActivatePipe)?;
 
-        for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-            ssh_wait_command.arg(ssh_opt);
-        }
+        info!("Creating activation waiter");
 
         let (send_activate, recv_activate) = tokio::sync::oneshot::channel();
         let (send_activated, recv_activated) = tokio::sync::oneshot::channel();
@@ -536,46 +1645,101 @@ pub async fn deploy_profile(
                 },
             };
 
+            // nobody is listening anymore if the deployment was interrupted or rejected meanwhile
             if let Some(err) = maybe_err {
-                send_activate.send(err).unwrap();
+                let _ = send_activate.send(err);
             }
 
-            send_activated.send(()).unwrap();
+            let _ = send_activated.send(());
         });
 
-        let mut ssh_wait_child = ssh_wait_command
-            .arg(self_wait_command)
-            .spawn()
-            .map_err(DeployProfileError::SSHWait)?;
-
-        if deploy_data
-            .merged_settings
-            .interactive_sudo
-            .unwrap_or(false)
-        {
-            trace!("[wait] Piping in sudo password");
-            handle_sudo_stdin(&mut ssh_wait_child, deploy_defs)
-                .await
-                .map_err(DeployProfileError::SSHActivatePipe)?;
-        }
-
-        tokio::select! {
-            x = ssh_wait_child.wait() => {
+        let waited = tokio::select! {
+            x = wait_for_activation(deploy_data, deploy_defs, &ssh_addr, &self_wait_command) => {
                 debug!("Wait command ended");
-                match x.map_err(DeployProfileError::SSHWait)?.code() {
-                    Some(0) => (),
-                    a => return Err(DeployProfileError::SSHWaitExit(a)),
-                };
+                x
             },
             x = recv_activate => {
                 debug!("Activate command exited with an error");
-                return Err(x.unwrap());
+                Err(match x.unwrap() {
+                    DeployProfileError::SSHActivateExit(a) => activate_exit_error(deploy_data, a),
+                    e => e,
+                })
             },
+        };
+
+        if let Err(ref e) = waited
+            && boot_check
+            && !interrupt.is_interrupted()
+            && matches!(
+                e,
+                DeployProfileError::SSHActivateExit(a) | DeployProfileError::SSHWaitExit(a)
+                    if is_ssh_connection_error(*a)
+            )
+        {
+            // the activation may have rebooted the node, which then waits in its boot-time check
+            warn!(
+                "Lost the connection to node `{}` while activating, waiting for it to come back",
+                deploy_data.node_name
+            );
+            wait_for_boot_check(deploy_data, deploy_defs, &ssh_addr, &self_wait_command).await?;
+        } else if let Err(e) = waited {
+            // the confirmation socket of a locked profile may well belong to the other deployment, and
+            // an activation whose script timed out is over
+            if !interrupt.is_interrupted()
+                || matches!(
+                    e,
+                    DeployProfileError::Locked(..) | DeployProfileError::ScriptTimeout(..)
+                )
+            {
+                return Err(e);
+            }
+
+            // Ctrl-C reaches our ssh processes too, while the activation carries on without them
+            warn!(
+                "Interrupted, waiting for the activation on node `{}` to reach a safe point (press Ctrl-C again to exit right away)",
+                deploy_data.node_name
+            );
+            wait_for_activation(deploy_data, deploy_defs, &ssh_addr, &self_wait_command).await?;
         }
 
-        info!("Success activating, attempting to confirm activation");
+        // the activation is waiting for confirmation from here on
+        let confirmed = async {
+            if interrupt.is_interrupted() && interrupt.action == InterruptAction::Revoke {
+                warn!(
+                    "Interrupted, rejecting the activation of profile `{}` on node `{}`",
+                    deploy_data.profile_name, deploy_data.node_name
+                );
+                reject_activation(deploy_data, deploy_defs, temp_path, &ssh_addr, &token).await?;
+                return Err(DeployProfileError::Rejected);
+            }
+
+            info!("Success activating, attempting to confirm activation");
 
-        let c = confirm_profile(deploy_data, deploy_defs, temp_path, &ssh_addr).await;
+            confirm_profile(deploy_data, deploy_defs, temp_path, &ssh_addr, &token).await?;
+            Ok(())
+        };
+        let c = match deploy_data.merged_settings.confirm_timeout_max {
+            // keeps the activation waiting for as long as the deployer is still working towards
+            // confirming it, e.g. while retrying over a flaky connection
+            Some(confirm_timeout_max) if confirm_timeout_max > confirm_timeout => {
+                tokio::select! {
+                    c = confirmed => c,
+                    _ = send_heartbeats(
+                        deploy_data,
+                        deploy_defs,
+                        temp_path,
+                        &ssh_addr,
+                        &token,
+                        confirm_timeout,
+                    ) => unreachable!("heartbeats are sent until confirmed"),
+                }
+            }
+            _ => confirmed.await,
+        };
+        // a rejected activation rolls back without waiting for its activate command
+        if matches!(c, Err(ref e) if !matches!(e, DeployProfileError::Confirm(_))) {
+            return c;
+        }
         recv_activated
             .await
             .map_err(DeployProfileError::SSHActivateTimeout)?;
@@ -613,16 +1777,12 @@ pub async fn revoke(
         debug_logs: deploy_data.debug_logs,
         log_dir: deploy_data.log_dir.as_deref(),
         no_emoji: deploy_data.no_emoji,
+        native_profiles: deploy_data.merged_settings.native_profiles.unwrap_or(false),
     });
 
     debug!("Constructed revoke command: {}", self_revoke_command);
 
-    let hostname = match deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &deploy_data.node.node_settings.hostname,
-    };
-
-    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+    let ssh_addr = deploy_data.ssh_addr(&deploy_defs.ssh_user);
 
     let mut ssh_activate_command = Command::new("ssh");
     ssh_activate_command
@@ -659,3 +1819,149 @@ pub async fn revoke(
         },
     }
 }
+
+#[derive(Error, Debug)]
+pub enum IsDeployedError {
+    #[error("Failed to run status command over SSH: {0}")]
+    SSH(std::io::Error),
+    #[error("Deployment data invalid: {0}")]
+    InvalidDeployDataDefs(#[from] DeployDataDefsError),
+}
+
+/// Whether the profile's closure is already active and confirmed on the node
+pub async fn is_deployed(
+    deploy_data: &crate::DeployData,
+    deploy_defs: &crate::DeployDefs,
+) -> Result<bool, IsDeployedError> {
+    let temp_path: &Path = match &deploy_data.merged_settings.temp_path {
+        Some(x) => x,
+        None => Path::new("/tmp"),
+    };
+
+    let self_status_command = build_status_command(&StatusCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: &deploy_data.profile.profile_settings.path,
+        profile_info: deploy_data.get_profile_info()?,
+        temp_path,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir.as_deref(),
+        no_emoji: deploy_data.no_emoji,
+    });
+
+    debug!("Constructed status command: {}", self_status_command);
+
+    let ssh_addr = deploy_data.ssh_addr(&deploy_defs.ssh_user);
+
+    let ssh_status_exit_status = RetryPolicy::from_settings(&deploy_data.merged_settings)
+        .run(
+            "Querying the profile status",
+            |res: &std::io::Result<std::process::ExitStatus>| {
+                matches!(res, Ok(status) if is_ssh_connection_error(status.code()))
+            },
+            || async {
+                let mut ssh_status_command = Command::new("ssh");
+                ssh_status_command
+                    .arg(&ssh_addr)
+                    .stdin(std::process::Stdio::piped());
+
+                for ssh_opt in &deploy_data.merged_settings.ssh_opts {
+                    ssh_status_command.arg(ssh_opt);
+                }
+
+                let mut ssh_status_child = ssh_status_command.arg(&self_status_command).spawn()?;
+
+                if deploy_data
+                    .merged_settings
+                    .interactive_sudo
+                    .unwrap_or(false)
+                {
+                    trace!("[status] Piping in sudo password");
+                    handle_sudo_stdin(&mut ssh_status_child, deploy_defs).await?;
+                }
+
+                ssh_status_child.wait().await
+            },
+        )
+        .await
+        .map_err(IsDeployedError::SSH)?;
+
+    // any failure, including the closure (and with it activate-rs) missing on the node, means
+    // that the profile still has to be deployed
+    Ok(ssh_status_exit_status.success())
+}
+
+#[derive(Error, Debug)]
+pub enum QueryStateError {
+    #[error("Failed to run state command over SSH: {0}")]
+    SSH(std::io::Error),
+    #[error("Querying the state over SSH resulted in a bad exit code: {0:?}")]
+    SSHExit(Option<i32>),
+    #[error("{0} isn't on the node yet, push or deploy it to read the state with its activate-rs")]
+    NotPushed(String),
+    #[error("Failed to parse the state reported by the node: {0}")]
+    Parse(serde_json::Error),
+    #[error("Deployment data invalid: {0}")]
+    InvalidDeployDataDefs(#[from] DeployDataDefsError),
+}
+
+/// The activation state the node recorded for the profile, if it was ever activated by deploy-rs
+pub async fn query_activation_state(
+    deploy_data: &crate::DeployData,
+    deploy_defs: &crate::DeployDefs,
+) -> Result<Option<crate::state::StateRecord>, QueryStateError> {
+    let self_state_command = build_state_command(&StateCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: &deploy_data.profile.profile_settings.path,
+        profile_info: deploy_data.get_profile_info()?,
+    });
+
+    debug!("Constructed state command: {}", self_state_command);
+
+    let ssh_addr = deploy_data.ssh_addr(&deploy_defs.ssh_user);
+
+    let output = RetryPolicy::from_settings(&deploy_data.merged_settings)
+        .run(
+            "Querying the activation state",
+            |res: &std::io::Result<std::process::Output>| {
+                matches!(res, Ok(output) if is_ssh_connection_error(output.status.code()))
+            },
+            || async {
+                let mut ssh_state_command = Command::new("ssh");
+                ssh_state_command
+                    .arg(&ssh_addr)
+                    .stdin(std::process::Stdio::piped())
+                    .stdout(std::process::Stdio::piped());
+
+                for ssh_opt in &deploy_data.merged_settings.ssh_opts {
+                    ssh_state_command.arg(ssh_opt);
+                }
+
+                let mut ssh_state_child = ssh_state_command.arg(&self_state_command).spawn()?;
+
+                if deploy_data
+                    .merged_settings
+                    .interactive_sudo
+                    .unwrap_or(false)
+                {
+                    trace!("[state] Piping in sudo password");
+                    handle_sudo_stdin(&mut ssh_state_child, deploy_defs).await?;
+                }
+
+                ssh_state_child.wait_with_output().await
+            },
+        )
+        .await
+        .map_err(QueryStateError::SSH)?;
+
+    match output.status.code() {
+        Some(0) => (),
+        Some(STATE_NOT_PUSHED_EXIT_CODE) => {
+            return Err(QueryStateError::NotPushed(
+                deploy_data.profile.profile_settings.path.clone(),
+            ));
+        }
+        a => return Err(QueryStateError::SSHExit(a)),
+    }
+
+    serde_json::from_slice(&output.stdout).map_err(QueryStateError::Parse)
+}
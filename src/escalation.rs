@@ -0,0 +1,159 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use serde::Deserialize;
+use std::fmt;
+
+/// The tool used on the target to run commands as the profile user
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PrivilegeEscalation {
+    /// `sudo`, or a compatible implementation such as `sudo-rs`
+    #[default]
+    Sudo,
+    Doas,
+    Run0,
+    Su,
+    /// Run everything as the SSH user
+    None,
+}
+
+impl fmt::Display for PrivilegeEscalation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrivilegeEscalation::Sudo => write!(f, "sudo"),
+            PrivilegeEscalation::Doas => write!(f, "doas"),
+            PrivilegeEscalation::Run0 => write!(f, "run0"),
+            PrivilegeEscalation::Su => write!(f, "su"),
+            PrivilegeEscalation::None => write!(f, "none"),
+        }
+    }
+}
+
+impl PrivilegeEscalation {
+    /// Whether the backend can read the password from stdin rather than from a terminal
+    pub fn supports_password_on_stdin(&self) -> bool {
+        matches!(self, PrivilegeEscalation::Sudo)
+    }
+
+    /// Whether the backend can keep the environment of the SSH user
+    pub fn supports_preserve_env(&self) -> bool {
+        matches!(self, PrivilegeEscalation::Sudo | PrivilegeEscalation::Su)
+    }
+}
+
+/// How to run a command with elevated privileges on the target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Escalation {
+    pub backend: PrivilegeEscalation,
+    /// Custom command replacing `sudo -u` (from the `sudo` setting), only used by the sudo backend
+    pub custom_command: Option<String>,
+    /// User to switch to, the backend's default (usually root) when not set
+    pub user: Option<String>,
+    pub password_on_stdin: bool,
+    pub preserve_env: bool,
+}
+
+impl Escalation {
+    /// Wraps a shell command so that it runs with elevated privileges
+    pub fn wrap(&self, command: &str) -> String {
+        match self.backend {
+            PrivilegeEscalation::Sudo => {
+                let mut prefix = match (&self.custom_command, &self.user) {
+                    (Some(custom), Some(user)) => format!("{} {}", custom, user),
+                    (None, Some(user)) => format!("sudo -u {}", user),
+                    (Some(custom), None) => custom.clone(),
+                    (None, None) => "sudo".to_string(),
+                };
+                // custom commands are expected to be configured to accept the password and keep the
+                // environment themselves, their flags for either are unknown
+                if self.custom_command.is_none() {
+                    if self.password_on_stdin {
+                        prefix = format!("{} -S -p \"\"", prefix);
+                    }
+                    if self.preserve_env {
+                        prefix = format!("{} -E", prefix);
+                    }
+                }
+                format!("{} {}", prefix, command)
+            }
+            PrivilegeEscalation::Doas => match &self.user {
+                Some(user) => format!("doas -u {} {}", user, command),
+                None => format!("doas {}", command),
+            },
+            PrivilegeEscalation::Run0 => match &self.user {
+                Some(user) => format!("run0 --user={} {}", user, command),
+                None => format!("run0 {}", command),
+            },
+            PrivilegeEscalation::Su => format!(
+                "su{} {} -c {}",
+                if self.preserve_env { " -m" } else { "" },
+                self.user.as_deref().unwrap_or("root"),
+                shlex::try_quote(command)
+                    .map(|x| x.to_string())
+                    .unwrap_or_else(|_| format!("'{}'", command)),
+            ),
+            PrivilegeEscalation::None => command.to_string(),
+        }
+    }
+}
+
+#[test]
+fn test_escalation_wrap() {
+    let escalation = |backend, user: Option<&str>| Escalation {
+        backend,
+        custom_command: None,
+        user: user.map(str::to_string),
+        password_on_stdin: false,
+        preserve_env: false,
+    };
+
+    assert_eq!(
+        escalation(PrivilegeEscalation::Sudo, Some("test")).wrap("id"),
+        "sudo -u test id"
+    );
+    assert_eq!(
+        Escalation {
+            password_on_stdin: true,
+            preserve_env: true,
+            ..escalation(PrivilegeEscalation::Sudo, Some("test"))
+        }
+        .wrap("id"),
+        "sudo -u test -S -p \"\" -E id"
+    );
+    assert_eq!(
+        Escalation {
+            password_on_stdin: true,
+            ..escalation(PrivilegeEscalation::Sudo, None)
+        }
+        .wrap("id"),
+        "sudo -S -p \"\" id"
+    );
+    assert_eq!(
+        Escalation {
+            custom_command: Some("doas -u".to_string()),
+            password_on_stdin: true,
+            preserve_env: true,
+            ..escalation(PrivilegeEscalation::Sudo, Some("test"))
+        }
+        .wrap("id"),
+        "doas -u test id"
+    );
+    assert_eq!(
+        escalation(PrivilegeEscalation::Doas, Some("test")).wrap("id"),
+        "doas -u test id"
+    );
+    assert_eq!(
+        escalation(PrivilegeEscalation::Run0, Some("test")).wrap("id"),
+        "run0 --user=test id"
+    );
+    assert_eq!(
+        escalation(PrivilegeEscalation::Su, Some("test")).wrap("rm '/tmp/x'"),
+        "su test -c \"rm '/tmp/x'\""
+    );
+    assert_eq!(
+        escalation(PrivilegeEscalation::None, Some("test")).wrap("id"),
+        "id"
+    );
+}
@@ -0,0 +1,513 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use std::fmt;
+
+use crate::DeployData;
+use crate::data::GenericSettings;
+use crate::escalation::PrivilegeEscalation;
+
+/// Where the effective value of a setting came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingSource {
+    Cli,
+    /// A `deploy.toml` configuration file, which sets the same overrides as the command line
+    Config,
+    SetProfile,
+    SetNode,
+    Profile,
+    Node,
+    TopLevel,
+    Default,
+    /// Derived from another setting, like the `-p` that `sshPort` adds to `sshOpts`
+    Setting(&'static str),
+}
+
+impl fmt::Display for SettingSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingSource::Cli => write!(f, "CLI flag"),
+            SettingSource::Config => write!(f, "deploy.toml"),
+            SettingSource::SetProfile => write!(f, "--set for profile"),
+            SettingSource::SetNode => write!(f, "--set for node"),
+            SettingSource::Profile => write!(f, "profile"),
+            SettingSource::Node => write!(f, "node"),
+            SettingSource::TopLevel => write!(f, "top-level"),
+            SettingSource::Default => write!(f, "default"),
+            SettingSource::Setting(name) => write!(f, "from {}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExplainedSetting {
+    pub name: &'static str,
+    pub value: String,
+    /// Usually a single source, `sshOpts` is appended across all levels so it may have several
+    pub sources: Vec<SettingSource>,
+}
+
+/// Tells apart values coming from the deploy definition from those replaced by a `--set` override
+fn refine_source(deploy_data: &DeployData, name: &str, source: SettingSource) -> SettingSource {
+    let overridden = |source| {
+        deploy_data.cmd_overrides.settings.iter().any(|o| {
+            o.key == name
+                && match source {
+                    SettingSource::Profile => {
+                        o.applies_to_profile(&deploy_data.node_name, &deploy_data.profile_name)
+                    }
+                    SettingSource::Node => o.applies_to_node(&deploy_data.node_name),
+                    _ => false,
+                }
+        })
+    };
+
+    match source {
+        SettingSource::Profile if overridden(source) => SettingSource::SetProfile,
+        SettingSource::Node if overridden(source) => SettingSource::SetNode,
+        _ => source,
+    }
+}
+
+/// Tells apart overrides read from `deploy.toml` from those given on the command line
+fn override_source(deploy_data: &DeployData, name: &str) -> SettingSource {
+    // the profile user is `profile-user` in `deploy.toml`, everything else is named the same
+    let key = match name {
+        "user" => "profile-user".to_string(),
+        _ => name.chars().fold(String::new(), |mut key, c| {
+            if c.is_ascii_uppercase() {
+                key.push('-');
+            }
+            key.push(c.to_ascii_lowercase());
+            key
+        }),
+    };
+
+    if deploy_data.cmd_overrides.from_config.contains(&key) {
+        SettingSource::Config
+    } else {
+        SettingSource::Cli
+    }
+}
+
+fn explain_layered(
+    deploy_data: &DeployData,
+    top_settings: &GenericSettings,
+    name: &'static str,
+    cli: Option<String>,
+    get: impl Fn(&GenericSettings) -> Option<String>,
+    default: String,
+) -> ExplainedSetting {
+    let (value, source) = match cli {
+        Some(v) => (v, override_source(deploy_data, name)),
+        None => [
+            (
+                SettingSource::Profile,
+                &deploy_data.profile.generic_settings,
+            ),
+            (SettingSource::Node, &deploy_data.node.generic_settings),
+            (SettingSource::TopLevel, top_settings),
+        ]
+        .into_iter()
+        .find_map(|(source, settings)| get(settings).map(|v| (v, source)))
+        .unwrap_or((default, SettingSource::Default)),
+    };
+
+    ExplainedSetting {
+        name,
+        value,
+        sources: vec![refine_source(deploy_data, name, source)],
+    }
+}
+
+fn explain_ssh_opts(deploy_data: &DeployData, top_settings: &GenericSettings) -> ExplainedSetting {
+    let settings = &deploy_data.merged_settings;
+    let mut value = Vec::new();
+    let mut sources = Vec::new();
+
+    // in the order `make_deploy_data` and the pinned host keys put them in front of sshOpts
+    if !deploy_data.node.node_settings.host_keys.is_empty() {
+        value.extend(
+            [
+                "-o",
+                "UserKnownHostsFile=<pinned hostKeys>",
+                "-o",
+                "GlobalKnownHostsFile=/dev/null",
+                "-o",
+                "StrictHostKeyChecking=yes",
+            ]
+            .map(str::to_string),
+        );
+        sources.push(SettingSource::Setting("hostKeys"));
+    }
+    value.extend(settings.ssh_opts.iter().cloned());
+    for (name, set) in [
+        ("sshPort", settings.ssh_port.is_some()),
+        ("sshIdentityFile", settings.ssh_identity_file.is_some()),
+        (
+            "jumpHosts",
+            settings.jump_hosts.as_ref().is_some_and(|x| !x.is_empty()),
+        ),
+    ] {
+        if set {
+            sources.push(SettingSource::Setting(name));
+        }
+    }
+
+    if deploy_data.cmd_overrides.ssh_opts.is_some() {
+        sources.push(override_source(deploy_data, "sshOpts"));
+    } else {
+        sources.extend(
+            [
+                (
+                    SettingSource::Profile,
+                    &deploy_data.profile.generic_settings,
+                ),
+                (SettingSource::Node, &deploy_data.node.generic_settings),
+                (SettingSource::TopLevel, top_settings),
+            ]
+            .into_iter()
+            .filter(|(_, settings)| !settings.ssh_opts.is_empty())
+            .map(|(source, _)| refine_source(deploy_data, "sshOpts", source)),
+        );
+    }
+
+    if sources.is_empty() {
+        sources.push(SettingSource::Default);
+    }
+
+    ExplainedSetting {
+        name: "sshOpts",
+        value: format!("{:?}", value),
+        sources,
+    }
+}
+
+/// Explains every generic setting of `deploy_data`, mirroring the merge done by `make_deploy_data`
+/// and the defaults applied later on during deployment
+pub fn explain_settings(
+    deploy_data: &DeployData,
+    top_settings: &GenericSettings,
+) -> Vec<ExplainedSetting> {
+    let overrides = &deploy_data.cmd_overrides;
+    let layered = |name, cli, get: fn(&GenericSettings) -> Option<String>, default: String| {
+        explain_layered(deploy_data, top_settings, name, cli, get, default)
+    };
+    let quoted = |s: &String| format!("{:?}", s);
+
+    let ssh_user = layered(
+        "sshUser",
+        overrides.ssh_user.as_ref().map(quoted),
+        |s| s.ssh_user.as_ref().map(|x| format!("{:?}", x)),
+        match whoami::username() {
+            Ok(u) => format!("{:?}", u),
+            Err(_) => "<local username>".to_string(),
+        },
+    );
+
+    let user = layered(
+        "user",
+        overrides.profile_user.as_ref().map(quoted),
+        |s| s.user.as_ref().map(|x| format!("{:?}", x)),
+        // the profile user falls back to the SSH user
+        ssh_user.value.clone(),
+    );
+
+    let privilege_escalation = layered(
+        "privilegeEscalation",
+        None,
+        |s| {
+            s.privilege_escalation
+                .map(|x| format!("{:?}", x.to_string()))
+        },
+        "\"sudo\"".to_string(),
+    );
+
+    // only the sudo backend runs the `sudo` command
+    let sudo = match deploy_data
+        .merged_settings
+        .privilege_escalation
+        .unwrap_or_default()
+    {
+        PrivilegeEscalation::Sudo => layered(
+            "sudo",
+            overrides.sudo.as_ref().map(quoted),
+            |s| s.sudo.as_ref().map(|x| format!("{:?}", x)),
+            "\"sudo -u\"".to_string(),
+        ),
+        backend => ExplainedSetting {
+            name: "sudo",
+            value: format!("unused, privilegeEscalation is {:?}", backend.to_string()),
+            sources: privilege_escalation.sources.clone(),
+        },
+    };
+
+    vec![
+        ExplainedSetting {
+            name: "hostname",
+            value: match deploy_data.hostname_candidates().as_slice() {
+                [hostname] => format!("{:?}", hostname),
+                candidates => format!("{:?}", candidates),
+            },
+            sources: vec![match overrides.hostname {
+                Some(_) => SettingSource::Cli,
+                None => refine_source(deploy_data, "hostname", SettingSource::Node),
+            }],
+        },
+        ssh_user,
+        user,
+        explain_ssh_opts(deploy_data, top_settings),
+        layered(
+            "compress",
+            overrides.compress.map(|x| x.to_string()),
+            |s| s.compress.map(|x| x.to_string()),
+            "false".to_string(),
+        ),
+        layered(
+            "fastConnection",
+            overrides.fast_connection.map(|x| x.to_string()),
+            |s| s.fast_connection.map(|x| x.to_string()),
+            "false".to_string(),
+        ),
+        layered(
+            "autoRollback",
+            overrides.auto_rollback.map(|x| x.to_string()),
+            |s| s.auto_rollback.map(|x| x.to_string()),
+            "true".to_string(),
+        ),
+        layered(
+            "confirmTimeout",
+            overrides.confirm_timeout.map(|x| x.to_string()),
+            |s| s.confirm_timeout.map(|x| x.to_string()),
+            "30".to_string(),
+        ),
+        layered(
+            "confirmTimeoutMax",
+            overrides.confirm_timeout_max.map(|x| x.to_string()),
+            |s| s.confirm_timeout_max.map(|x| x.to_string()),
+            "none".to_string(),
+        ),
+        layered(
+            "activationTimeout",
+            overrides.activation_timeout.map(|x| x.to_string()),
+            |s| s.activation_timeout.map(|x| x.to_string()),
+            "240".to_string(),
+        ),
+        layered(
+            "scriptTimeout",
+            overrides.script_timeout.map(|x| x.to_string()),
+            |s| s.script_timeout.map(|x| x.to_string()),
+            "none".to_string(),
+        ),
+        layered(
+            "tempPath",
+            overrides
+                .temp_path
+                .as_ref()
+                .map(|x| format!("{:?}", x.display().to_string())),
+            |s| {
+                s.temp_path
+                    .as_ref()
+                    .map(|x| format!("{:?}", x.display().to_string()))
+            },
+            "\"/tmp\"".to_string(),
+        ),
+        layered(
+            "magicRollback",
+            overrides.magic_rollback.map(|x| x.to_string()),
+            |s| s.magic_rollback.map(|x| x.to_string()),
+            "true".to_string(),
+        ),
+        layered(
+            "bootCheck",
+            overrides.boot_check.map(|x| x.to_string()),
+            |s| s.boot_check.map(|x| x.to_string()),
+            "false".to_string(),
+        ),
+        layered(
+            "nativeProfiles",
+            overrides.native_profiles.map(|x| x.to_string()),
+            |s| s.native_profiles.map(|x| x.to_string()),
+            "false".to_string(),
+        ),
+        sudo,
+        layered(
+            "remoteBuild",
+            // `--remote-build` can only turn remote building on
+            overrides.remote_build.then(|| "true".to_string()),
+            |s| s.remote_build.map(|x| x.to_string()),
+            "false".to_string(),
+        ),
+        layered(
+            "interactiveSudo",
+            overrides.interactive_sudo.map(|x| x.to_string()),
+            |s| s.interactive_sudo.map(|x| x.to_string()),
+            "false".to_string(),
+        ),
+        layered(
+            "sudoPasswordCommand",
+            None,
+            |s| s.sudo_password_command.as_ref().map(|x| format!("{:?}", x)),
+            "none".to_string(),
+        ),
+        privilege_escalation,
+        layered(
+            "preserveEnv",
+            None,
+            |s| s.preserve_env.map(|x| x.to_string()),
+            "false".to_string(),
+        ),
+        layered(
+            "signingKeys",
+            None,
+            |s| s.signing_keys.as_ref().map(|x| format!("{:?}", x)),
+            "none".to_string(),
+        ),
+        layered(
+            "sshPort",
+            None,
+            |s| s.ssh_port.map(|x| x.to_string()),
+            "none".to_string(),
+        ),
+        layered(
+            "sshIdentityFile",
+            None,
+            |s| s.ssh_identity_file.as_ref().map(|x| format!("{:?}", x)),
+            "none".to_string(),
+        ),
+        layered(
+            "jumpHosts",
+            None,
+            |s| {
+                s.jump_hosts
+                    .as_ref()
+                    .map(|x| format!("{:?}", x.iter().map(|x| x.to_string()).collect::<Vec<_>>()))
+            },
+            "none".to_string(),
+        ),
+        layered(
+            "retries",
+            None,
+            |s| s.retries.map(|x| x.to_string()),
+            "2".to_string(),
+        ),
+        layered(
+            "retryDelay",
+            None,
+            |s| s.retry_delay.map(|x| x.to_string()),
+            "1".to_string(),
+        ),
+        layered(
+            "skipUnchanged",
+            overrides.skip_unchanged.then(|| "true".to_string()),
+            |s| s.skip_unchanged.map(|x| x.to_string()),
+            "false".to_string(),
+        ),
+    ]
+}
+
+/// Renders the output of `explain_settings` as an aligned, human readable table
+pub fn format_explanation(settings: &[ExplainedSetting]) -> String {
+    let name_width = settings.iter().map(|s| s.name.len()).max().unwrap_or(0);
+    let value_width = settings.iter().map(|s| s.value.len()).max().unwrap_or(0);
+
+    settings
+        .iter()
+        .map(|s| {
+            format!(
+                "  {:name_width$} = {:value_width$}  ({})",
+                s.name,
+                s.value,
+                s.sources
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[test]
+fn test_explain_settings() {
+    let data: crate::data::Data = serde_json::from_str(
+        r#"{
+            "sshUser": "top",
+            "magicRollback": true,
+            "sshOpts": ["-p", "2222"],
+            "privilegeEscalation": "doas",
+            "nodes": {
+                "foo": {
+                    "hostname": "foo.example.com",
+                    "magicRollback": false,
+                    "confirmTimeout": 60,
+                    "sshOpts": ["-v"],
+                    "sshPort": 22,
+                    "profiles": {
+                        "system": {
+                            "path": "/nix/store/blah-system",
+                            "user": "root"
+                        }
+                    }
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+    let node = &data.nodes["foo"];
+    let profile = &node.node_settings.profiles["system"];
+    let cmd_overrides = crate::CmdOverrides {
+        confirm_timeout: Some(90),
+        activation_timeout: Some(600),
+        settings: vec!["foo.system.sshOpts=-A".parse().unwrap()],
+        from_config: vec!["activation-timeout".to_string()],
+        ..Default::default()
+    };
+    let deploy_data = crate::make_deploy_data(
+        &data.generic_settings,
+        node,
+        "foo".to_string(),
+        profile,
+        "system".to_string(),
+        &cmd_overrides,
+        false,
+        None,
+        false,
+    );
+
+    let explained = explain_settings(&deploy_data, &data.generic_settings);
+    let get = |name: &str| explained.iter().find(|s| s.name == name).unwrap();
+
+    assert_eq!(get("sshUser").value, "\"top\"");
+    assert_eq!(get("sshUser").sources, vec![SettingSource::TopLevel]);
+    assert_eq!(get("user").value, "\"root\"");
+    assert_eq!(get("user").sources, vec![SettingSource::Profile]);
+    assert_eq!(get("magicRollback").value, "false");
+    assert_eq!(get("magicRollback").sources, vec![SettingSource::Node]);
+    assert_eq!(get("confirmTimeout").value, "90");
+    assert_eq!(get("confirmTimeout").sources, vec![SettingSource::Cli]);
+    assert_eq!(get("tempPath").value, "\"/tmp\"");
+    assert_eq!(get("tempPath").sources, vec![SettingSource::Default]);
+    assert_eq!(get("activationTimeout").value, "600");
+    assert_eq!(
+        get("activationTimeout").sources,
+        vec![SettingSource::Config]
+    );
+    assert_eq!(get("sudo").value, "unused, privilegeEscalation is \"doas\"");
+    assert_eq!(get("sudo").sources, vec![SettingSource::TopLevel]);
+    assert_eq!(
+        get("sshOpts").value,
+        "[\"-p\", \"22\", \"-A\", \"-v\", \"-p\", \"2222\"]"
+    );
+    assert_eq!(
+        get("sshOpts").sources,
+        vec![
+            SettingSource::Setting("sshPort"),
+            SettingSource::SetProfile,
+            SettingSource::Node,
+            SettingSource::TopLevel
+        ]
+    );
+}
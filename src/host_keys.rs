@@ -0,0 +1,83 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::{debug, warn};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// A temporary known_hosts file pinning the host keys of a single node, removed when dropped
+#[derive(Debug)]
+pub struct KnownHostsFile {
+    path: PathBuf,
+}
+
+/// Renders host keys (e.g. `ssh-ed25519 AAAA...`) as known_hosts entries.
+///
+/// The file is only ever used for one node, so the entries match any host name. This keeps them
+/// valid regardless of the port, IP address or alias used to reach the node.
+fn format_known_hosts(host_keys: &[String]) -> String {
+    host_keys
+        .iter()
+        .map(|key| format!("* {}\n", key.trim()))
+        .collect()
+}
+
+#[test]
+fn test_format_known_hosts() {
+    assert_eq!(
+        format_known_hosts(&[
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA".to_string(),
+            " ecdsa-sha2-nistp256 AAAAE2VjZHNh root@host\n".to_string()
+        ]),
+        "* ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA\n* ecdsa-sha2-nistp256 AAAAE2VjZHNh root@host\n"
+    );
+}
+
+impl KnownHostsFile {
+    pub fn create(node_name: &str, host_keys: &[String]) -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "deploy-rs-known-hosts-{}-{}",
+            std::process::id(),
+            node_name
+        ));
+
+        debug!(
+            "Writing host keys of node `{}` to {}",
+            node_name,
+            path.display()
+        );
+
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?
+            .write_all(format_known_hosts(host_keys).as_bytes())?;
+
+        Ok(KnownHostsFile { path })
+    }
+
+    /// SSH options making ssh (and Nix, through `NIX_SSHOPTS`) only accept the pinned keys
+    pub fn ssh_opts(&self) -> Vec<String> {
+        vec![
+            "-o".to_string(),
+            format!("UserKnownHostsFile={}", self.path.display()),
+            "-o".to_string(),
+            "GlobalKnownHostsFile=/dev/null".to_string(),
+            "-o".to_string(),
+            "StrictHostKeyChecking=yes".to_string(),
+        ]
+    }
+}
+
+impl Drop for KnownHostsFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(
+                "Failed to remove known hosts file {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
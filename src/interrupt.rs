@@ -0,0 +1,49 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use serde::{Deserialize, Serialize};
+use signal_hook::consts::SIGINT;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// What happens to an activation that is waiting for confirmation when the deployment is interrupted
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum InterruptAction {
+    /// Roll the activation back right away
+    #[default]
+    Revoke,
+    /// Confirm the activation as if the deployment had carried on
+    Confirm,
+}
+
+/// Tracks whether the user asked to stop the deployment with Ctrl-C
+#[derive(Debug, Clone)]
+pub struct Interrupt {
+    flag: Arc<AtomicBool>,
+    pub action: InterruptAction,
+}
+
+impl Interrupt {
+    /// Not interrupted until `listen` is called
+    pub fn new(action: InterruptAction) -> Self {
+        Interrupt {
+            flag: Arc::new(AtomicBool::new(false)),
+            action,
+        }
+    }
+
+    /// Replaces the default Ctrl-C handling: the first Ctrl-C sets the flag, a second one exits
+    /// right away with the usual exit code
+    pub fn listen(&self) -> std::io::Result<()> {
+        // the shutdown condition is checked before the flag is set, so the first Ctrl-C only sets it
+        signal_hook::flag::register_conditional_shutdown(SIGINT, 130, Arc::clone(&self.flag))?;
+        signal_hook::flag::register(SIGINT, Arc::clone(&self.flag))?;
+        Ok(())
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,185 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// A step of the deployment of a single profile that has been completed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    /// The closure is on the node, either copied there or built there
+    Pushed,
+    /// The closure was activated and, with magic rollback, confirmed
+    Deployed,
+    /// The deployment failed
+    Failed,
+    /// The activation was rolled back, after succeeding or when the deployment was interrupted
+    RolledBack,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub node: String,
+    pub profile: String,
+    /// The closure being deployed
+    pub path: String,
+    pub phase: Phase,
+}
+
+#[derive(Error, Debug)]
+pub enum LoadJournalError {
+    #[error("Failed to read run journal {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to parse line {1} of run journal {0}: {2}")]
+    Parse(PathBuf, usize, serde_json::Error),
+}
+
+/// Append-only record of the completed phases of a deployment run, used by `--resume`
+#[derive(Debug, Clone)]
+pub struct RunJournal {
+    pub run_id: String,
+    path: PathBuf,
+    /// What this process recorded, even if writing it to the file failed
+    recorded: Arc<Mutex<Vec<JournalEntry>>>,
+}
+
+impl RunJournal {
+    /// A new identifier, unique enough to tell the runs of a single machine apart
+    pub fn new_run_id() -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+        format!("{}-{}", timestamp, std::process::id())
+    }
+
+    pub fn new(dir: &Path, run_id: &str) -> Self {
+        RunJournal {
+            run_id: run_id.to_string(),
+            path: dir.join(format!("deploy-rs-run-{}.jsonl", run_id)),
+            recorded: Arc::default(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends an entry. Failing to do so only costs the ability to resume, so it's not fatal.
+    pub fn record(&self, node: &str, profile: &str, path: &str, phase: Phase) {
+        let entry = JournalEntry {
+            node: node.to_string(),
+            profile: profile.to_string(),
+            path: path.to_string(),
+            phase,
+        };
+        self.recorded.lock().unwrap().push(entry.clone());
+
+        let res = (|| -> std::io::Result<()> {
+            if let Some(dir) = self.path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let mut line = serde_json::to_vec(&entry)?;
+            line.push(b'\n');
+            // a single append of a whole line, so concurrent writers don't interleave entries
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?
+                .write_all(&line)
+        })();
+
+        if let Err(e) = res {
+            warn!(
+                "Failed to write to run journal {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    /// Removes the journal file, once there is nothing left to resume
+    pub fn remove(&self) {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => warn!(
+                "Failed to remove run journal {}: {}",
+                self.path.display(),
+                e
+            ),
+            _ => (),
+        }
+    }
+
+    /// The entries recorded by this process, without those of an earlier run that is resumed
+    pub fn recorded(&self) -> Vec<JournalEntry> {
+        self.recorded.lock().unwrap().clone()
+    }
+
+    pub fn load(&self) -> Result<Vec<JournalEntry>, LoadJournalError> {
+        let contents = std::fs::read_to_string(&self.path)
+            .map_err(|e| LoadJournalError::Read(self.path.clone(), e))?;
+
+        contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .map_err(|e| LoadJournalError::Parse(self.path.clone(), i + 1, e))
+            })
+            .collect()
+    }
+}
+
+/// The last phase recorded for deploying `path` as `profile` of `node`
+pub fn last_phase(
+    entries: &[JournalEntry],
+    node: &str,
+    profile: &str,
+    path: &str,
+) -> Option<Phase> {
+    entries
+        .iter()
+        .rev()
+        .find(|x| x.node == node && x.profile == profile && x.path == path)
+        .map(|x| x.phase)
+}
+
+#[test]
+fn test_run_journal() {
+    let dir = std::env::temp_dir().join(format!("deploy-rs-journal-test-{}", std::process::id()));
+    let journal = RunJournal::new(&dir, "test");
+
+    journal.record("foo", "system", "/nix/store/a-system", Phase::Pushed);
+    journal.record("foo", "system", "/nix/store/a-system", Phase::Deployed);
+    journal.record("bar", "system", "/nix/store/b-system", Phase::Pushed);
+    journal.record("bar", "system", "/nix/store/b-system", Phase::Failed);
+
+    let entries = journal.load().unwrap();
+    journal.remove();
+    assert!(!journal.path().exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(entries.len(), 4);
+    assert_eq!(journal.recorded(), entries);
+    assert_eq!(
+        last_phase(&entries, "foo", "system", "/nix/store/a-system"),
+        Some(Phase::Deployed)
+    );
+    assert_eq!(
+        last_phase(&entries, "bar", "system", "/nix/store/b-system"),
+        Some(Phase::Failed)
+    );
+    // a different closure means that the profile changed since
+    assert_eq!(
+        last_phase(&entries, "foo", "system", "/nix/store/c-system"),
+        None
+    );
+}
@@ -10,20 +10,28 @@ use merge::Merge;
 
 use thiserror::Error;
 
-use std::path::{Path, PathBuf};
-
-pub fn make_lock_path(temp_path: &Path, closure: &str) -> PathBuf {
-    let lock_hash = &closure["/nix/store/".len()..closure.find('-').unwrap_or(closure.len())];
-    temp_path.join(format!("deploy-rs-canary-{}", lock_hash))
-}
+use std::path::PathBuf;
 
 pub mod cli;
+pub mod config;
+pub mod confirmation;
 pub mod data;
 pub mod deploy;
+pub mod escalation;
+pub mod explain;
+pub mod host_keys;
+pub mod interrupt;
+pub mod journal;
+pub mod lock;
 pub mod logging;
+pub mod preflight;
+pub mod profile;
 pub mod push;
+pub mod retry;
+pub mod secrets;
+pub mod state;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct CmdOverrides {
     pub ssh_user: Option<String>,
     pub profile_user: Option<String>,
@@ -33,13 +41,221 @@ pub struct CmdOverrides {
     pub auto_rollback: Option<bool>,
     pub hostname: Option<String>,
     pub magic_rollback: Option<bool>,
+    pub boot_check: Option<bool>,
+    pub native_profiles: Option<bool>,
     pub temp_path: Option<PathBuf>,
     pub confirm_timeout: Option<u16>,
+    pub confirm_timeout_max: Option<u16>,
     pub activation_timeout: Option<u16>,
+    pub script_timeout: Option<u16>,
     pub sudo: Option<String>,
     pub interactive_sudo: Option<bool>,
     pub dry_activate: bool,
     pub remote_build: bool,
+    pub skip_unchanged: bool,
+    pub force_unlock: bool,
+    pub settings: Vec<SettingOverride>,
+    /// Keys of the overrides above that were read from `deploy.toml` rather than given on the
+    /// command line
+    pub from_config: Vec<String>,
+}
+
+/// A single `--set` override, scoped to a node or to one profile of a node
+#[derive(Debug, Clone)]
+pub struct SettingOverride {
+    pub node: String,
+    pub profile: Option<String>,
+    pub key: String,
+    pub value: String,
+    settings: data::GenericSettings,
+}
+
+#[derive(Error, Debug)]
+pub enum ParseSettingOverrideError {
+    #[error("Expected an override of the form `node.key=value` or `node.profile.key=value`")]
+    MissingValue,
+    #[error("Unbalanced quotes in override path `{0}`")]
+    UnbalancedQuotes(String),
+    #[error("Expected an override path of the form `node.key` or `node.profile.key`, got `{0}`")]
+    BadPath(String),
+    #[error("Unknown setting `{0}`, expected one of: hostname, {1}")]
+    UnknownKey(String, String),
+    #[error("`hostname` can only be overridden for a whole node")]
+    ProfileHostname,
+    #[error("Invalid value for setting `{0}`: {1}")]
+    InvalidValue(String, serde_json::Error),
+}
+
+fn split_setting_path(path: &str) -> Result<Vec<String>, ParseSettingOverrideError> {
+    let mut parts = vec![String::new()];
+    let mut quoted = false;
+
+    for c in path.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '.' if !quoted => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+
+    if quoted {
+        return Err(ParseSettingOverrideError::UnbalancedQuotes(
+            path.to_string(),
+        ));
+    }
+
+    Ok(parts)
+}
+
+fn parse_setting_value(
+    key: &str,
+    value: &str,
+) -> Result<data::GenericSettings, ParseSettingOverrideError> {
+    let to_settings = |v: serde_json::Value| {
+        let mut object = serde_json::Map::new();
+        object.insert(key.to_string(), v);
+        serde_json::from_value(serde_json::Value::Object(object))
+    };
+
+    // Accept JSON values (`true`, `60`, `["-p", "22"]`), but fall back to treating the value as a
+    // plain string so that `sshUser=admin` works without extra quoting. `sshOpts` given as a plain
+    // string are split the same way as `--ssh-opts`
+    let fallback = if key == "sshOpts" {
+        serde_json::Value::from(
+            shlex::split(value).unwrap_or(value.split(' ').map(|x| x.to_owned()).collect()),
+        )
+    } else {
+        serde_json::Value::String(value.to_string())
+    };
+
+    match serde_json::from_str(value).map(to_settings) {
+        Ok(Ok(settings)) => Ok(settings),
+        _ => to_settings(fallback)
+            .map_err(|e| ParseSettingOverrideError::InvalidValue(key.to_string(), e)),
+    }
+}
+
+impl std::str::FromStr for SettingOverride {
+    type Err = ParseSettingOverrideError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, value) = s
+            .split_once('=')
+            .ok_or(ParseSettingOverrideError::MissingValue)?;
+
+        let (node, profile, key) = match &split_setting_path(path)?[..] {
+            [node, key] => (node.clone(), None, key.clone()),
+            [node, profile, key] => (node.clone(), Some(profile.clone()), key.clone()),
+            _ => return Err(ParseSettingOverrideError::BadPath(path.to_string())),
+        };
+
+        if node.is_empty() || profile.as_ref().is_some_and(String::is_empty) {
+            return Err(ParseSettingOverrideError::BadPath(path.to_string()));
+        }
+
+        let settings = if key == "hostname" {
+            if profile.is_some() {
+                return Err(ParseSettingOverrideError::ProfileHostname);
+            }
+            serde_json::from_str("{}").unwrap()
+        } else if data::GENERIC_SETTINGS_KEYS.contains(&key.as_str()) {
+            parse_setting_value(&key, value)?
+        } else {
+            return Err(ParseSettingOverrideError::UnknownKey(
+                key,
+                data::GENERIC_SETTINGS_KEYS.join(", "),
+            ));
+        };
+
+        Ok(SettingOverride {
+            node,
+            profile,
+            key,
+            value: value.to_string(),
+            settings,
+        })
+    }
+}
+
+impl std::fmt::Display for SettingOverride {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.profile {
+            Some(profile) => write!(
+                f,
+                "{:?}.{:?}.{}={}",
+                self.node, profile, self.key, self.value
+            ),
+            None => write!(f, "{:?}.{}={}", self.node, self.key, self.value),
+        }
+    }
+}
+
+impl SettingOverride {
+    fn applies_to_node(&self, node_name: &str) -> bool {
+        self.profile.is_none() && self.node == node_name
+    }
+
+    fn applies_to_profile(&self, node_name: &str, profile_name: &str) -> bool {
+        self.node == node_name && self.profile.as_deref() == Some(profile_name)
+    }
+
+    /// Overrides this setting in `settings`, which is the layer the override is scoped to
+    fn apply(&self, settings: &mut data::GenericSettings) {
+        // `sshOpts` are appended across levels, an override should replace the level's options
+        if self.key == "sshOpts" {
+            settings.ssh_opts.clear();
+        }
+
+        let mut overridden = self.settings.clone();
+        overridden.merge(settings.clone());
+        *settings = overridden;
+    }
+}
+
+#[test]
+fn test_parse_setting_override() {
+    let o: SettingOverride = "foo.sshUser=admin".parse().unwrap();
+    assert_eq!(o.node, "foo");
+    assert_eq!(o.profile, None);
+    assert_eq!(o.settings.ssh_user, Some("admin".to_string()));
+
+    let o: SettingOverride = "\"foo.example.com\".system.confirmTimeout=60"
+        .parse()
+        .unwrap();
+    assert_eq!(o.node, "foo.example.com");
+    assert_eq!(o.profile, Some("system".to_string()));
+    assert_eq!(o.settings.confirm_timeout, Some(60));
+
+    let o: SettingOverride = "foo.sshOpts=[\"-p\", \"2222\"]".parse().unwrap();
+    assert_eq!(o.settings.ssh_opts, vec!["-p", "2222"]);
+
+    let o: SettingOverride = "foo.sshOpts=-p 2222".parse().unwrap();
+    assert_eq!(o.settings.ssh_opts, vec!["-p", "2222"]);
+
+    let o: SettingOverride = "foo.hostname=10.0.0.1".parse().unwrap();
+    assert_eq!(o.key, "hostname");
+    assert_eq!(o.value, "10.0.0.1");
+
+    assert!(matches!(
+        "foo.sshUser".parse::<SettingOverride>(),
+        Err(ParseSettingOverrideError::MissingValue)
+    ));
+    assert!(matches!(
+        "sshUser=admin".parse::<SettingOverride>(),
+        Err(ParseSettingOverrideError::BadPath(_))
+    ));
+    assert!(matches!(
+        "foo.sshUsr=admin".parse::<SettingOverride>(),
+        Err(ParseSettingOverrideError::UnknownKey(..))
+    ));
+    assert!(matches!(
+        "foo.system.hostname=bar".parse::<SettingOverride>(),
+        Err(ParseSettingOverrideError::ProfileHostname)
+    ));
+    assert!(matches!(
+        "foo.confirmTimeout=soon".parse::<SettingOverride>(),
+        Err(ParseSettingOverrideError::InvalidValue(..))
+    ));
 }
 
 #[derive(PartialEq, Debug)]
@@ -191,6 +407,106 @@ fn test_parse_flake() {
     );
 }
 
+#[test]
+fn test_cmd_overrides() {
+    let data: data::Data = serde_json::from_str(
+        r#"{
+            "nodes": {
+                "foo": {
+                    "hostname": "foo.example.com",
+                    "tempPath": "/var/tmp",
+                    "sudo": "doas -u",
+                    "profiles": {
+                        "system": {
+                            "path": "/nix/store/blah-system"
+                        }
+                    }
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+    let node = &data.nodes["foo"];
+    let profile = &node.node_settings.profiles["system"];
+    let merged_settings = |cmd_overrides: &CmdOverrides| {
+        make_deploy_data(
+            &data.generic_settings,
+            node,
+            "foo".to_string(),
+            profile,
+            "system".to_string(),
+            cmd_overrides,
+            false,
+            None,
+            false,
+        )
+        .merged_settings
+    };
+
+    let merged = merged_settings(&CmdOverrides::default());
+    assert_eq!(merged.temp_path, Some(PathBuf::from("/var/tmp")));
+    assert_eq!(merged.sudo.as_deref(), Some("doas -u"));
+
+    let merged = merged_settings(&CmdOverrides {
+        temp_path: Some(PathBuf::from("/run/deploy-rs")),
+        sudo: Some("sudo -u".to_string()),
+        ..Default::default()
+    });
+    assert_eq!(merged.temp_path, Some(PathBuf::from("/run/deploy-rs")));
+    assert_eq!(merged.sudo.as_deref(), Some("sudo -u"));
+}
+
+#[test]
+fn test_ssh_settings() {
+    let data: data::Data = serde_json::from_str(
+        r#"{
+            "sshOpts": ["-p", "2200", "-v"],
+            "nodes": {
+                "foo": {
+                    "hostname": "foo.example.com",
+                    "sshPort": 2222,
+                    "sshIdentityFile": "/etc/deploy/id_ed25519",
+                    "jumpHosts": [{ "hostname": "bastion" }],
+                    "profiles": {
+                        "system": {
+                            "path": "/nix/store/blah-system"
+                        }
+                    }
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+    let node = &data.nodes["foo"];
+    let deploy_data = make_deploy_data(
+        &data.generic_settings,
+        node,
+        "foo".to_string(),
+        &node.node_settings.profiles["system"],
+        "system".to_string(),
+        &CmdOverrides::default(),
+        false,
+        None,
+        false,
+    );
+
+    // ssh keeps the first `-p`, so `sshPort` has to come before the one in `sshOpts`
+    assert_eq!(
+        deploy_data.merged_settings.ssh_opts,
+        vec![
+            "-p",
+            "2222",
+            "-i",
+            "/etc/deploy/id_ed25519",
+            "-J",
+            "bastion",
+            "-p",
+            "2200",
+            "-v"
+        ]
+    );
+}
+
 pub fn parse_file<'a>(
     file: &'a str,
     attribute: &'a str,
@@ -219,6 +535,9 @@ pub struct DeployData {
     pub log_dir: Option<String>,
     pub no_emoji: bool,
 
+    /// Identifies the deployment in the profile lock taken during activation
+    pub run_id: Option<String>,
+
     pub progressbar: Option<indicatif::ProgressBar>,
 }
 
@@ -226,7 +545,7 @@ pub struct DeployData {
 pub struct DeployDefs {
     pub ssh_user: String,
     pub profile_user: String,
-    pub sudo: Option<String>,
+    pub sudo: Option<escalation::Escalation>,
     pub sudo_password: Option<String>,
 }
 enum ProfileInfo {
@@ -246,9 +565,74 @@ pub enum DeployDataDefsError {
 
     #[error("Error obtaining local username: {0}")]
     Whoami(whoami::Error),
+
+    #[error("Privilege escalation with {0} doesn't support `{1}` (node {2})")]
+    UnsupportedEscalation(escalation::PrivilegeEscalation, &'static str, String),
+}
+
+/// Strips the brackets around an IPv6 literal, which plain ssh doesn't accept
+fn unbracket_host(hostname: &str) -> &str {
+    hostname
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(hostname)
+}
+
+/// Brackets IPv6 literals so that they can be used in a URL or next to a port
+pub(crate) fn bracket_host(hostname: &str) -> String {
+    if hostname.contains(':') && !hostname.starts_with('[') {
+        format!("[{}]", hostname)
+    } else {
+        hostname.to_string()
+    }
+}
+
+#[test]
+fn test_host_bracketing() {
+    assert_eq!(unbracket_host("[fd00::1]"), "fd00::1");
+    assert_eq!(unbracket_host("fd00::1"), "fd00::1");
+    assert_eq!(unbracket_host("example.com"), "example.com");
+    assert_eq!(bracket_host("fd00::1"), "[fd00::1]");
+    assert_eq!(bracket_host("[fd00::1]"), "[fd00::1]");
+    assert_eq!(bracket_host("10.0.0.1"), "10.0.0.1");
 }
 
 impl DeployData {
+    /// The host to connect to, taking `--hostname` into account
+    pub fn hostname(&self) -> &str {
+        match self.cmd_overrides.hostname {
+            Some(ref x) => x,
+            None => &self.node.node_settings.hostname,
+        }
+    }
+
+    /// Addresses the node may be reached at, in order of preference
+    pub fn hostname_candidates(&self) -> Vec<String> {
+        if let Some(ref x) = self.cmd_overrides.hostname {
+            return vec![x.clone()];
+        }
+
+        let mut candidates: Vec<String> = Vec::new();
+        for hostname in std::iter::once(&self.node.node_settings.hostname)
+            .chain(&self.node.node_settings.hostnames)
+        {
+            if !hostname.is_empty() && !candidates.contains(hostname) {
+                candidates.push(hostname.clone());
+            }
+        }
+        candidates
+    }
+
+    /// Destination for plain ssh invocations
+    pub fn ssh_addr(&self, ssh_user: &str) -> String {
+        format!("{}@{}", ssh_user, unbracket_host(self.hostname()))
+    }
+
+    /// Authority of the `ssh://` and `ssh-ng://` store URLs for the node
+    pub fn store_addr(&self, ssh_user: &str) -> String {
+        format!("{}@{}", ssh_user, bracket_host(self.hostname()))
+    }
+
     pub fn defs(&self) -> Result<DeployDefs, DeployDataDefsError> {
         let ssh_user = match self.merged_settings.ssh_user {
             Some(ref u) => u.clone(),
@@ -257,8 +641,50 @@ impl DeployData {
 
         let profile_user = self.get_profile_user()?;
 
-        let sudo: Option<String> = match self.merged_settings.user {
-            Some(ref user) if user != &ssh_user => Some(format!("{} {}", self.get_sudo(), user)),
+        let backend = self
+            .merged_settings
+            .privilege_escalation
+            .unwrap_or_default();
+        let interactive_sudo = self.merged_settings.interactive_sudo.unwrap_or(false);
+        let preserve_env = self.merged_settings.preserve_env.unwrap_or(false);
+
+        if interactive_sudo && !backend.supports_password_on_stdin() {
+            return Err(DeployDataDefsError::UnsupportedEscalation(
+                backend,
+                "interactiveSudo",
+                self.node_name.clone(),
+            ));
+        }
+        if preserve_env && !backend.supports_preserve_env() {
+            return Err(DeployDataDefsError::UnsupportedEscalation(
+                backend,
+                "preserveEnv",
+                self.node_name.clone(),
+            ));
+        }
+
+        let user = match self.merged_settings.user {
+            Some(ref user) if user != &ssh_user => Some(user.clone()),
+            _ => None,
+        };
+        let custom_command = match backend {
+            escalation::PrivilegeEscalation::Sudo => self.merged_settings.sudo.clone(),
+            _ => None,
+        };
+
+        // With interactive sudo, commands are run through sudo even if no user switch is needed,
+        // unless a custom sudo command is set (which would need a user name)
+        let sudo = match backend {
+            escalation::PrivilegeEscalation::None => None,
+            _ if user.is_some() || (interactive_sudo && custom_command.is_none()) => {
+                Some(escalation::Escalation {
+                    backend,
+                    custom_command,
+                    user,
+                    password_on_stdin: interactive_sudo,
+                    preserve_env,
+                })
+            }
             _ => None,
         };
 
@@ -286,13 +712,6 @@ impl DeployData {
         Ok(profile_user)
     }
 
-    fn get_sudo(&self) -> String {
-        match self.merged_settings.sudo {
-            Some(ref x) => x.clone(),
-            None => "sudo -u".to_string(),
-        }
-    }
-
     fn get_profile_info(&self) -> Result<ProfileInfo, DeployDataDefsError> {
         match self.profile.profile_settings.profile_path {
             Some(ref profile_path) => Ok(ProfileInfo::ProfilePath {
@@ -309,6 +728,7 @@ impl DeployData {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn make_deploy_data(
     top_settings: &data::GenericSettings,
     node: &data::Node,
@@ -320,6 +740,23 @@ pub fn make_deploy_data(
     log_dir: Option<String>,
     no_emoji: bool,
 ) -> DeployData {
+    let mut node = node.clone();
+    let mut profile = profile.clone();
+
+    // `--set` overrides replace the values at the level they are scoped to
+    for o in &cmd_overrides.settings {
+        if o.applies_to_node(&node_name) {
+            if o.key == "hostname" {
+                node.node_settings.hostname = o.value.clone();
+                node.node_settings.hostnames.clear();
+            } else {
+                o.apply(&mut node.generic_settings);
+            }
+        } else if o.applies_to_profile(&node_name, &profile_name) {
+            o.apply(&mut profile.generic_settings);
+        }
+    }
+
     let mut merged_settings = profile.generic_settings.clone();
     merged_settings.merge(node.generic_settings.clone());
     merged_settings.merge(top_settings.clone());
@@ -328,6 +765,9 @@ pub fn make_deploy_data(
     if cmd_overrides.remote_build {
         merged_settings.remote_build = Some(cmd_overrides.remote_build);
     }
+    if cmd_overrides.skip_unchanged {
+        merged_settings.skip_unchanged = Some(cmd_overrides.skip_unchanged);
+    }
     if cmd_overrides.ssh_user.is_some() {
         merged_settings.ssh_user = cmd_overrides.ssh_user.clone();
     }
@@ -350,26 +790,72 @@ pub fn make_deploy_data(
     if let Some(magic_rollback) = cmd_overrides.magic_rollback {
         merged_settings.magic_rollback = Some(magic_rollback);
     }
+    if let Some(boot_check) = cmd_overrides.boot_check {
+        merged_settings.boot_check = Some(boot_check);
+    }
+    if let Some(native_profiles) = cmd_overrides.native_profiles {
+        merged_settings.native_profiles = Some(native_profiles);
+    }
     if let Some(confirm_timeout) = cmd_overrides.confirm_timeout {
         merged_settings.confirm_timeout = Some(confirm_timeout);
     }
+    if let Some(confirm_timeout_max) = cmd_overrides.confirm_timeout_max {
+        merged_settings.confirm_timeout_max = Some(confirm_timeout_max);
+    }
     if let Some(activation_timeout) = cmd_overrides.activation_timeout {
         merged_settings.activation_timeout = Some(activation_timeout);
     }
+    if let Some(script_timeout) = cmd_overrides.script_timeout {
+        merged_settings.script_timeout = Some(script_timeout);
+    }
     if let Some(interactive_sudo) = cmd_overrides.interactive_sudo {
         merged_settings.interactive_sudo = Some(interactive_sudo);
     }
+    if cmd_overrides.temp_path.is_some() {
+        merged_settings.temp_path = cmd_overrides.temp_path.clone();
+    }
+    if cmd_overrides.sudo.is_some() {
+        merged_settings.sudo = cmd_overrides.sudo.clone();
+    }
+
+    // every ssh invocation and NIX_SSHOPTS use sshOpts, so this reaches all connections to the node
+    if let Some(ref jump_hosts) = merged_settings.jump_hosts
+        && !jump_hosts.is_empty()
+    {
+        let jump_hosts = jump_hosts
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        merged_settings
+            .ssh_opts
+            .splice(0..0, ["-J".to_string(), jump_hosts]);
+    }
+
+    // prepended, since ssh uses the first `-p` it is given and tries identities in order
+    if let Some(ref ssh_identity_file) = merged_settings.ssh_identity_file {
+        merged_settings.ssh_opts.splice(
+            0..0,
+            ["-i".to_string(), ssh_identity_file.display().to_string()],
+        );
+    }
+    if let Some(ssh_port) = merged_settings.ssh_port {
+        merged_settings
+            .ssh_opts
+            .splice(0..0, ["-p".to_string(), ssh_port.to_string()]);
+    }
 
     DeployData {
         node_name,
-        node: node.clone(),
+        node,
         profile_name,
-        profile: profile.clone(),
+        profile,
         cmd_overrides: cmd_overrides.clone(),
         merged_settings,
         debug_logs,
         log_dir,
         no_emoji,
+        run_id: None,
         progressbar: None,
     }
 }
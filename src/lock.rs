@@ -0,0 +1,220 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// The deployment holding a profile lock
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LockOwner {
+    /// User running the deployer
+    pub user: String,
+    /// Machine the deployer runs on
+    pub host: String,
+    /// Unix time the lock was taken at
+    pub started: u64,
+    pub run_id: Option<String>,
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+impl LockOwner {
+    /// The owner of a lock taken now
+    pub fn new(user: String, host: String, run_id: Option<String>) -> Self {
+        LockOwner {
+            user,
+            host,
+            started: now(),
+            run_id,
+        }
+    }
+}
+
+pub(crate) fn format_age(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+impl std::fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}@{} since {} ago",
+            self.user,
+            self.host,
+            format_age(now().saturating_sub(self.started))
+        )?;
+        if let Some(run_id) = &self.run_id {
+            write!(f, " (run {})", run_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// The user and host of the deployer running in this process
+pub fn deployer() -> (String, String) {
+    (
+        whoami::username().unwrap_or_else(|_| "unknown".to_string()),
+        whoami::hostname().unwrap_or_else(|_| "unknown".to_string()),
+    )
+}
+
+/// Next to the profile, so that every deployment of it agrees on the lock regardless of its `tempPath`
+pub fn make_profile_lock_path(profile_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.deploy-rs-lock", profile_path))
+}
+
+#[derive(Error, Debug)]
+pub enum AcquireLockError {
+    #[error("{0} is locked by {1}, pass --force-unlock to the deployer if that deployment is gone")]
+    Locked(String, LockOwner),
+    #[error(
+        "{0} is locked by a deployment that didn't record its owner, pass --force-unlock to the deployer if it is gone"
+    )]
+    LockedUnknown(String),
+    #[error("Failed to take the deployment lock {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+}
+
+/// Exclusive lock on a profile for the duration of an activation, released when dropped
+#[derive(Debug)]
+pub struct ProfileLock {
+    path: PathBuf,
+}
+
+impl ProfileLock {
+    /// Takes the lock for `profile_path`, first removing a lock held by somebody else if `force`
+    pub fn acquire(
+        profile_path: &str,
+        owner: &LockOwner,
+        force: bool,
+    ) -> Result<Self, AcquireLockError> {
+        let path = make_profile_lock_path(profile_path);
+        let io_error = |e| AcquireLockError::Io(path.clone(), e);
+
+        // the owner is written to a file of our own first and then linked into place, so the lock
+        // never exists without its owner
+        let tmp_path = PathBuf::from(format!(
+            "{}.{}-{}",
+            path.display(),
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.subsec_nanos())
+                .unwrap_or(0)
+        ));
+        let contents = serde_json::to_vec(owner).map_err(|e| io_error(e.into()))?;
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+            .and_then(|mut file| file.write_all(&contents))
+            .map_err(io_error)?;
+
+        let res = loop {
+            match std::fs::hard_link(&tmp_path, &path) {
+                Ok(()) => break Ok(ProfileLock { path: path.clone() }),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    let holder = read_owner(&path);
+
+                    if !force {
+                        break Err(match holder {
+                            Some(holder) => {
+                                AcquireLockError::Locked(profile_path.to_string(), holder)
+                            }
+                            None => AcquireLockError::LockedUnknown(profile_path.to_string()),
+                        });
+                    }
+
+                    match holder {
+                        Some(holder) => warn!("Removing the lock held by {}", holder),
+                        None => warn!("Removing the lock held by an unknown deployment"),
+                    }
+                    match std::fs::remove_file(&path) {
+                        Ok(()) => (),
+                        // released meanwhile
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                        Err(e) => break Err(io_error(e)),
+                    }
+                }
+                Err(e) => break Err(io_error(e)),
+            }
+        };
+
+        if let Err(e) = std::fs::remove_file(&tmp_path) {
+            warn!("Failed to remove {}: {}", tmp_path.display(), e);
+        }
+        res
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ProfileLock {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(
+                "Failed to release the deployment lock {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn read_owner(path: &Path) -> Option<LockOwner> {
+    serde_json::from_slice(&std::fs::read(path).ok()?).ok()
+}
+
+#[test]
+fn test_profile_lock() {
+    let dir = std::env::temp_dir().join(format!("deploy-rs-lock-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let profile_path = dir.join("system").display().to_string();
+
+    let alice = LockOwner {
+        user: "alice".to_string(),
+        host: "laptop".to_string(),
+        started: now() - 125,
+        run_id: Some("1-2".to_string()),
+    };
+    let bob = LockOwner::new("bob".to_string(), "ci".to_string(), None);
+
+    let lock = ProfileLock::acquire(&profile_path, &alice, false).unwrap();
+    assert!(lock.path().exists());
+
+    match ProfileLock::acquire(&profile_path, &bob, false) {
+        Err(AcquireLockError::Locked(_, holder)) => {
+            assert_eq!(holder, alice);
+            assert_eq!(holder.to_string(), "alice@laptop since 2m ago (run 1-2)");
+        }
+        x => panic!("expected the profile to be locked, got {:?}", x),
+    }
+
+    // a stale lock is taken over
+    std::mem::forget(lock);
+    let lock = ProfileLock::acquire(&profile_path, &bob, true).unwrap();
+    assert_eq!(read_owner(lock.path()), Some(bob));
+
+    drop(lock);
+    assert!(!make_profile_lock_path(&profile_path).exists());
+    // only the lock itself was left behind, and it's gone now
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,391 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::debug;
+use std::fmt;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::retry::{RetryPolicy, is_ssh_connection_error};
+use crate::{DeployData, DeployDefs};
+
+/// Free space in `tempPath` below which activation is likely to fail, in KiB
+const MIN_TEMP_SPACE_KIB: u64 = 1024;
+/// Oldest Nix release the copy and activation commands are known to work with
+const MIN_NIX_VERSION: (u32, u32) = (2, 3);
+/// Remote builds rely on `--eval-store`, which appeared in Nix 2.4
+const MIN_REMOTE_BUILD_NIX_VERSION: (u32, u32) = (2, 4);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreflightCheck {
+    Ssh,
+    Sudo,
+    NixEnv,
+    NixVersion,
+    TempSpace,
+}
+
+impl fmt::Display for PreflightCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreflightCheck::Ssh => write!(f, "ssh"),
+            PreflightCheck::Sudo => write!(f, "sudo"),
+            PreflightCheck::NixEnv => write!(f, "nix-env"),
+            PreflightCheck::NixVersion => write!(f, "nix version"),
+            PreflightCheck::TempSpace => write!(f, "temp space"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckOutcome {
+    Passed(String),
+    Failed(String),
+    Skipped(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub check: PreflightCheck,
+    pub outcome: CheckOutcome,
+}
+
+/// The preflight results of a single node
+#[derive(Debug, Clone)]
+pub struct NodePreflight {
+    pub node_name: String,
+    pub results: Vec<CheckResult>,
+}
+
+impl NodePreflight {
+    pub fn passed(&self) -> bool {
+        !self
+            .results
+            .iter()
+            .any(|x| matches!(x.outcome, CheckOutcome::Failed(_)))
+    }
+}
+
+/// Extracts `(major, minor)` from the output of `nix-store --version`
+fn parse_nix_version(output: &str) -> Option<(u32, u32)> {
+    let version = output.split_whitespace().last()?;
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts
+        .next()?
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect::<String>()
+        .parse()
+        .ok()?;
+    Some((major, minor))
+}
+
+/// Extracts the available space in KiB from the output of `df -Pk`
+fn parse_df_available(output: &str) -> Option<u64> {
+    output
+        .lines()
+        .nth(1)?
+        .split_whitespace()
+        .nth(3)?
+        .parse()
+        .ok()
+}
+
+#[test]
+fn test_parse_preflight_output() {
+    assert_eq!(parse_nix_version("nix-store (Nix) 2.18.1\n"), Some((2, 18)));
+    assert_eq!(
+        parse_nix_version("nix-env (Nix) 2.19.0pre20231003_dirty"),
+        Some((2, 19))
+    );
+    assert_eq!(
+        parse_nix_version("nix-store (Lix, like Nix) 2.91.1"),
+        Some((2, 91))
+    );
+    assert_eq!(parse_nix_version("command not found"), None);
+
+    assert_eq!(
+        parse_df_available(
+            "Filesystem     1024-blocks    Used Available Capacity Mounted on\n\
+             tmpfs              8144376    1204   8143172       1% /tmp\n"
+        ),
+        Some(8143172)
+    );
+    assert_eq!(parse_df_available(""), None);
+}
+
+/// Runs a command on the node, feeding it the sudo password if one is given
+async fn run_ssh(
+    deploy_data: &DeployData,
+    deploy_defs: &DeployDefs,
+    command: &str,
+    password: Option<&str>,
+) -> std::io::Result<std::process::Output> {
+    debug!(
+        "Preflight command for {}: {}",
+        deploy_data.node_name, command
+    );
+
+    RetryPolicy::from_settings(&deploy_data.merged_settings)
+        .run(
+            "Preflight check",
+            |res: &std::io::Result<std::process::Output>| matches!(res, Ok(output) if is_ssh_connection_error(output.status.code())),
+            || async {
+                let mut ssh_command = Command::new("ssh");
+                ssh_command
+                    .arg(deploy_data.ssh_addr(&deploy_defs.ssh_user))
+                    .stdin(if password.is_some() {
+                        Stdio::piped()
+                    } else {
+                        Stdio::null()
+                    })
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped());
+
+                for ssh_opt in &deploy_data.merged_settings.ssh_opts {
+                    ssh_command.arg(ssh_opt);
+                }
+
+                // stdin only carries the sudo password, ssh itself must not wait for a prompt
+                let mut child = ssh_command
+                    .arg("-o")
+                    .arg("BatchMode=yes")
+                    .arg("-o")
+                    .arg("ConnectTimeout=10")
+                    .arg(command)
+                    .spawn()?;
+
+                if let Some(password) = password {
+                    let mut stdin = child
+                        .stdin
+                        .take()
+                        .expect("child did not have a stdin handle");
+                    // sudo may exit before reading the password, so errors here are not interesting
+                    let _ = stdin.write_all(format!("{}\n", password).as_bytes()).await;
+                }
+
+                child.wait_with_output().await
+            },
+        )
+        .await
+}
+
+/// Short description of why a command failed, for the preflight table
+fn describe_failure(output: &std::process::Output) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match stderr.lines().rev().find(|x| !x.trim().is_empty()) {
+        Some(line) => format!("exit code {:?}: {}", output.status.code(), line.trim()),
+        None => format!("exit code {:?}", output.status.code()),
+    }
+}
+
+/// Runs `command` and turns its output into a check outcome with `parse`
+async fn check_command(
+    deploy_data: &DeployData,
+    deploy_defs: &DeployDefs,
+    command: &str,
+    parse: impl Fn(&str) -> CheckOutcome,
+) -> CheckOutcome {
+    match run_ssh(deploy_data, deploy_defs, command, None).await {
+        Ok(output) if output.status.success() => parse(&String::from_utf8_lossy(&output.stdout)),
+        Ok(output) => CheckOutcome::Failed(describe_failure(&output)),
+        Err(e) => CheckOutcome::Failed(format!("failed to run ssh: {}", e)),
+    }
+}
+
+/// Checks everything a deployment needs from a node before anything is built. `profiles` are all
+/// profiles being deployed to the node, they have to share the node and must not be empty.
+pub async fn preflight_node(profiles: &[(&DeployData, &DeployDefs)]) -> NodePreflight {
+    let (deploy_data, deploy_defs) = profiles[0];
+    let mut results = Vec::new();
+
+    let ssh_outcome = match run_ssh(deploy_data, deploy_defs, "true", None).await {
+        Ok(output) if output.status.success() => {
+            CheckOutcome::Passed(deploy_data.ssh_addr(&deploy_defs.ssh_user))
+        }
+        Ok(output) => CheckOutcome::Failed(describe_failure(&output)),
+        Err(e) => CheckOutcome::Failed(format!("failed to run ssh: {}", e)),
+    };
+    let reachable = matches!(ssh_outcome, CheckOutcome::Passed(_));
+    results.push(CheckResult {
+        check: PreflightCheck::Ssh,
+        outcome: ssh_outcome,
+    });
+
+    if !reachable {
+        for check in [
+            PreflightCheck::Sudo,
+            PreflightCheck::NixEnv,
+            PreflightCheck::NixVersion,
+            PreflightCheck::TempSpace,
+        ] {
+            results.push(CheckResult {
+                check,
+                outcome: CheckOutcome::Skipped("node is unreachable".to_string()),
+            });
+        }
+
+        return NodePreflight {
+            node_name: deploy_data.node_name.clone(),
+            results,
+        };
+    }
+
+    // profiles using the same escalation only need to be checked once
+    let mut checked_commands: Vec<String> = Vec::new();
+    for (deploy_data, deploy_defs) in profiles {
+        let escalation = match &deploy_defs.sudo {
+            Some(x) => x,
+            None => continue,
+        };
+
+        let command = escalation.wrap("true");
+        if checked_commands.contains(&command) {
+            continue;
+        }
+        checked_commands.push(command.clone());
+
+        let password = if escalation.password_on_stdin {
+            deploy_defs.sudo_password.as_deref()
+        } else {
+            None
+        };
+
+        let outcome = match run_ssh(deploy_data, deploy_defs, &command, password).await {
+            Ok(output) if output.status.success() => {
+                CheckOutcome::Passed(format!("can run commands as {}", deploy_defs.profile_user))
+            }
+            Ok(output) => CheckOutcome::Failed(format!(
+                "`{}` failed with {}",
+                command,
+                describe_failure(&output)
+            )),
+            Err(e) => CheckOutcome::Failed(format!("failed to run ssh: {}", e)),
+        };
+        results.push(CheckResult {
+            check: PreflightCheck::Sudo,
+            outcome,
+        });
+    }
+    if checked_commands.is_empty() {
+        results.push(CheckResult {
+            check: PreflightCheck::Sudo,
+            outcome: CheckOutcome::Skipped("not needed".to_string()),
+        });
+    }
+
+    // native profiles don't run `nix-env` on the node at all
+    let nix_env_outcome = if profiles
+        .iter()
+        .all(|(deploy_data, _)| deploy_data.merged_settings.native_profiles.unwrap_or(false))
+    {
+        CheckOutcome::Skipped("not needed".to_string())
+    } else {
+        check_command(deploy_data, deploy_defs, "command -v nix-env", |stdout| {
+            CheckOutcome::Passed(stdout.trim().to_string())
+        })
+        .await
+    };
+    results.push(CheckResult {
+        check: PreflightCheck::NixEnv,
+        outcome: nix_env_outcome,
+    });
+
+    let min_version = if profiles
+        .iter()
+        .any(|(deploy_data, _)| deploy_data.merged_settings.remote_build.unwrap_or(false))
+    {
+        MIN_REMOTE_BUILD_NIX_VERSION
+    } else {
+        MIN_NIX_VERSION
+    };
+    results.push(CheckResult {
+        check: PreflightCheck::NixVersion,
+        outcome: check_command(deploy_data, deploy_defs, "nix-store --version", |stdout| {
+            match parse_nix_version(stdout) {
+                Some(version) if version >= min_version => {
+                    CheckOutcome::Passed(stdout.trim().to_string())
+                }
+                Some(_) => CheckOutcome::Failed(format!(
+                    "{}, at least {}.{} is required",
+                    stdout.trim(),
+                    min_version.0,
+                    min_version.1
+                )),
+                None => CheckOutcome::Failed(format!("unrecognized version `{}`", stdout.trim())),
+            }
+        })
+        .await,
+    });
+
+    let temp_path: &Path = match &deploy_data.merged_settings.temp_path {
+        Some(x) => x,
+        None => Path::new("/tmp"),
+    };
+    let temp_path_str = temp_path.display().to_string();
+    let df_command = format!(
+        "df -Pk {}",
+        shlex::try_quote(&temp_path_str).unwrap_or(temp_path_str.as_str().into())
+    );
+    results.push(CheckResult {
+        check: PreflightCheck::TempSpace,
+        outcome: check_command(deploy_data, deploy_defs, &df_command, |stdout| {
+            match parse_df_available(stdout) {
+                Some(available) if available >= MIN_TEMP_SPACE_KIB => {
+                    CheckOutcome::Passed(format!("{} KiB free in {}", available, temp_path_str))
+                }
+                Some(available) => CheckOutcome::Failed(format!(
+                    "only {} KiB free in {}",
+                    available, temp_path_str
+                )),
+                None => CheckOutcome::Failed(format!("unrecognized df output `{}`", stdout.trim())),
+            }
+        })
+        .await,
+    });
+
+    NodePreflight {
+        node_name: deploy_data.node_name.clone(),
+        results,
+    }
+}
+
+/// Renders preflight results as an aligned table with one row per check
+pub fn format_preflight(nodes: &[NodePreflight]) -> String {
+    let rows: Vec<[String; 4]> = nodes
+        .iter()
+        .flat_map(|node| {
+            node.results.iter().map(|result| {
+                let (status, detail) = match &result.outcome {
+                    CheckOutcome::Passed(x) => ("ok", x),
+                    CheckOutcome::Failed(x) => ("FAILED", x),
+                    CheckOutcome::Skipped(x) => ("skipped", x),
+                };
+                [
+                    node.node_name.clone(),
+                    result.check.to_string(),
+                    status.to_string(),
+                    detail.clone(),
+                ]
+            })
+        })
+        .collect();
+
+    let node_width = rows.iter().map(|x| x[0].len()).max().unwrap_or(0);
+    let check_width = rows.iter().map(|x| x[1].len()).max().unwrap_or(0);
+    let status_width = rows.iter().map(|x| x[2].len()).max().unwrap_or(0);
+
+    rows.iter()
+        .map(|[node, check, status, detail]| {
+            format!(
+                "  {:node_width$}  {:check_width$}  {:status_width$}  {}",
+                node, check, status, detail
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,331 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::warn;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::state::Generation;
+
+#[derive(Error, Debug)]
+pub enum ProfileError {
+    #[error("Failed to read the generations of profile {0}: {1}")]
+    List(String, std::io::Error),
+    #[error("Failed to resolve {0}: {1}")]
+    Resolve(PathBuf, std::io::Error),
+    #[error("Failed to create generation {0}: {1}")]
+    Create(PathBuf, std::io::Error),
+    #[error("Failed to switch profile {0}: {1}")]
+    Switch(String, std::io::Error),
+    #[error("Failed to delete generation {0}: {1}")]
+    Delete(PathBuf, std::io::Error),
+    #[error("Profile {0} has no generation {1}")]
+    NoSuchGeneration(String, u64),
+    #[error("Profile {0} has no generation before the current one")]
+    NoPreviousGeneration(String),
+    #[error("Refusing to delete generation {1} of profile {0}, it is the current one")]
+    DeleteCurrent(String, u64),
+}
+
+/// `<profile>-<number>-link`, next to the profile where `nix-env` creates its generations too
+pub fn make_generation_path(profile_path: &str, number: u64) -> PathBuf {
+    PathBuf::from(format!("{}-{}-link", profile_path, number))
+}
+
+/// The directory of the profile and the name its generations are named after
+fn split_profile_path(profile_path: &str) -> (PathBuf, String) {
+    let path = Path::new(profile_path);
+    let dir = match path.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = path
+        .file_name()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    (dir, name)
+}
+
+/// The number of a generation from its `<name>-<number>-link` file name
+fn parse_generation_name(name: &str, file_name: &str) -> Option<u64> {
+    file_name
+        .strip_prefix(name)?
+        .strip_prefix('-')?
+        .strip_suffix("-link")?
+        .parse()
+        .ok()
+}
+
+/// The generations of the profile, oldest first
+pub fn list_generations(profile_path: &str) -> Result<Vec<Generation>, ProfileError> {
+    let (dir, name) = split_profile_path(profile_path);
+    let list_error = |e| ProfileError::List(profile_path.to_string(), e);
+
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(list_error(e)),
+    };
+
+    let mut generations = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(list_error)?;
+        let Some(number) = parse_generation_name(&name, &entry.file_name().to_string_lossy())
+        else {
+            continue;
+        };
+        let path =
+            std::fs::read_link(entry.path()).map_err(|e| ProfileError::Resolve(entry.path(), e))?;
+        generations.push(Generation {
+            number,
+            path: path.display().to_string(),
+        });
+    }
+    generations.sort_by_key(|x| x.number);
+
+    Ok(generations)
+}
+
+/// The generation the profile points to, if the profile exists yet
+pub fn current_generation(profile_path: &str) -> Result<Option<Generation>, ProfileError> {
+    let target = match std::fs::read_link(profile_path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(ProfileError::Resolve(PathBuf::from(profile_path), e)),
+    };
+
+    let (_, name) = split_profile_path(profile_path);
+    let Some(number) = target
+        .file_name()
+        .and_then(|x| parse_generation_name(&name, &x.to_string_lossy()))
+    else {
+        return Ok(None);
+    };
+
+    let link = make_generation_path(profile_path, number);
+    let path = std::fs::read_link(&link).map_err(|e| ProfileError::Resolve(link, e))?;
+
+    Ok(Some(Generation {
+        number,
+        path: path.display().to_string(),
+    }))
+}
+
+/// Points the profile at a generation. The new symlink is renamed over the old one, so the profile
+/// points to one generation or the other at any time, even after a crash.
+fn switch_link(profile_path: &str, number: u64) -> Result<(), ProfileError> {
+    let (dir, name) = split_profile_path(profile_path);
+    let switch_error = |e| ProfileError::Switch(profile_path.to_string(), e);
+
+    let staging = dir.join(format!("{}.tmp-{}", name, std::process::id()));
+    match std::fs::remove_file(&staging) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(switch_error(e)),
+        _ => (),
+    }
+
+    // relative, like the links `nix-env` creates, so that the profile directory can be moved
+    std::os::unix::fs::symlink(format!("{}-{}-link", name, number), &staging)
+        .map_err(switch_error)?;
+    std::fs::rename(&staging, profile_path).map_err(switch_error)
+}
+
+/// Registers a generation outside the profiles directory as a garbage collector root, through
+/// `gcroots/auto` like `nix-env` does for profiles in home directories
+fn register_gc_root(nix_state_dir: &Path, link: &Path) -> std::io::Result<()> {
+    let profiles_dir = nix_state_dir.join("profiles");
+    let profiles_dir = profiles_dir.canonicalize().unwrap_or(profiles_dir);
+    let link = std::path::absolute(link)?;
+    if let Some(dir) = link.parent().and_then(|x| x.canonicalize().ok())
+        && dir.starts_with(&profiles_dir)
+    {
+        return Ok(());
+    }
+
+    let mut hasher = DefaultHasher::new();
+    link.hash(&mut hasher);
+    // the collector removes roots whose generation is gone by itself
+    let root = nix_state_dir
+        .join("gcroots/auto")
+        .join(format!("{:016x}", hasher.finish()));
+    match std::fs::remove_file(&root) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    std::os::unix::fs::symlink(&link, &root)
+}
+
+/// Sets the profile to `closure` like `nix-env --set`: a new generation is only created if the
+/// latest one doesn't point to `closure` already. Returns the generation the profile points to.
+pub fn set_profile(
+    profile_path: &str,
+    closure: &str,
+    nix_state_dir: &Path,
+) -> Result<u64, ProfileError> {
+    let (dir, _) = split_profile_path(profile_path);
+    std::fs::create_dir_all(&dir).map_err(|e| ProfileError::Create(dir.clone(), e))?;
+
+    let number = match list_generations(profile_path)?.last() {
+        Some(last) if last.path == closure => last.number,
+        last => {
+            let number = last.map(|x| x.number).unwrap_or(0) + 1;
+            let link = make_generation_path(profile_path, number);
+            std::os::unix::fs::symlink(closure, &link)
+                .map_err(|e| ProfileError::Create(link.clone(), e))?;
+
+            // an unrooted generation could be collected right after the profile switched to it
+            if let Err(e) = register_gc_root(nix_state_dir, &link) {
+                if let Err(e) = std::fs::remove_file(&link) {
+                    warn!("Failed to remove {}: {}", link.display(), e);
+                }
+                return Err(ProfileError::Create(link, e));
+            }
+            number
+        }
+    };
+
+    switch_link(profile_path, number)?;
+
+    Ok(number)
+}
+
+/// Points the profile at an existing generation, like `nix-env --switch-generation`
+pub fn switch_generation(profile_path: &str, number: u64) -> Result<(), ProfileError> {
+    if std::fs::symlink_metadata(make_generation_path(profile_path, number)).is_err() {
+        return Err(ProfileError::NoSuchGeneration(
+            profile_path.to_string(),
+            number,
+        ));
+    }
+
+    switch_link(profile_path, number)
+}
+
+/// Points the profile at the generation before the current one, like `nix-env --rollback`.
+/// Returns the generation the profile points to now.
+pub fn roll_back(profile_path: &str) -> Result<u64, ProfileError> {
+    let no_previous = || ProfileError::NoPreviousGeneration(profile_path.to_string());
+
+    let current = current_generation(profile_path)?
+        .ok_or_else(no_previous)?
+        .number;
+    let previous = list_generations(profile_path)?
+        .into_iter()
+        .map(|x| x.number)
+        .rfind(|x| *x < current)
+        .ok_or_else(no_previous)?;
+
+    switch_link(profile_path, previous)?;
+
+    Ok(previous)
+}
+
+/// Deletes a generation other than the current one, so that its closure can be garbage collected
+pub fn delete_generation(profile_path: &str, number: u64) -> Result<(), ProfileError> {
+    if current_generation(profile_path)?.is_some_and(|x| x.number == number) {
+        return Err(ProfileError::DeleteCurrent(
+            profile_path.to_string(),
+            number,
+        ));
+    }
+
+    let link = make_generation_path(profile_path, number);
+    match std::fs::remove_file(&link) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(ProfileError::NoSuchGeneration(
+            profile_path.to_string(),
+            number,
+        )),
+        Err(e) => Err(ProfileError::Delete(link, e)),
+    }
+}
+
+#[test]
+fn test_native_profile() {
+    let dir = std::env::temp_dir().join(format!("deploy-rs-profile-test-{}", std::process::id()));
+    let nix_state_dir = dir.join("state");
+    std::fs::create_dir_all(nix_state_dir.join("profiles")).unwrap();
+    std::fs::create_dir_all(nix_state_dir.join("gcroots/auto")).unwrap();
+    let home = dir.join("home/profiles/home").display().to_string();
+    let system = nix_state_dir.join("profiles/system").display().to_string();
+    let (a, b, c) = ("/nix/store/aaa-a", "/nix/store/bbb-b", "/nix/store/ccc-c");
+
+    assert_eq!(current_generation(&home).unwrap(), None);
+    assert_eq!(set_profile(&home, a, &nix_state_dir).unwrap(), 1);
+    // the latest generation is reused, like `nix-env --set` does
+    assert_eq!(set_profile(&home, a, &nix_state_dir).unwrap(), 1);
+    assert_eq!(set_profile(&home, b, &nix_state_dir).unwrap(), 2);
+    assert_eq!(
+        current_generation(&home).unwrap(),
+        Some(Generation {
+            number: 2,
+            path: b.to_string(),
+        })
+    );
+    assert_eq!(std::fs::read_link(&home).unwrap(), Path::new("home-2-link"));
+    assert_eq!(
+        list_generations(&home)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.number)
+            .collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+
+    // only the generations outside the profiles directory need roots of their own
+    set_profile(&system, a, &nix_state_dir).unwrap();
+    let roots = std::fs::read_dir(nix_state_dir.join("gcroots/auto"))
+        .unwrap()
+        .count();
+    assert_eq!(roots, 2);
+
+    assert_eq!(roll_back(&home).unwrap(), 1);
+    assert_eq!(current_generation(&home).unwrap().unwrap().path, a);
+    assert!(matches!(
+        roll_back(&home),
+        Err(ProfileError::NoPreviousGeneration(..))
+    ));
+    assert!(matches!(
+        delete_generation(&home, 1),
+        Err(ProfileError::DeleteCurrent(..))
+    ));
+    delete_generation(&home, 2).unwrap();
+    assert!(matches!(
+        switch_generation(&home, 2),
+        Err(ProfileError::NoSuchGeneration(..))
+    ));
+    // numbers keep counting from the latest generation that is left
+    assert_eq!(set_profile(&home, c, &nix_state_dir).unwrap(), 2);
+    switch_generation(&home, 1).unwrap();
+    assert_eq!(current_generation(&home).unwrap().unwrap().number, 1);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_native_profile_gc_root_failure() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("deploy-rs-gcroot-test-{}", std::process::id()));
+    let nix_state_dir = dir.join("state");
+    let roots = nix_state_dir.join("gcroots/auto");
+    std::fs::create_dir_all(&roots).unwrap();
+    std::fs::set_permissions(&roots, std::fs::Permissions::from_mode(0o555)).unwrap();
+    // root can write to the read-only directory anyway, a file in its place fails for everyone
+    if unsafe { libc::geteuid() } == 0 {
+        std::fs::remove_dir(&roots).unwrap();
+        std::fs::write(&roots, "").unwrap();
+    }
+    let home = dir.join("home/profiles/home").display().to_string();
+
+    assert!(matches!(
+        set_profile(&home, "/nix/store/aaa-a", &nix_state_dir),
+        Err(ProfileError::Create(..))
+    ));
+    assert!(list_generations(&home).unwrap().is_empty());
+    assert_eq!(current_generation(&home).unwrap(), None);
+
+    std::fs::set_permissions(&roots, std::fs::Permissions::from_mode(0o755)).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -2,12 +2,15 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
+use crate::data::SigningKey;
+use crate::retry::{RetryPolicy, is_ssh_connection_error};
 use indicatif::ProgressBar;
 use log::{debug, info, warn};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use thiserror::Error;
 use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncWriteExt;
 use tokio::io::BufReader;
 use tokio::process::Child;
 use tokio::process::Command;
@@ -50,6 +53,29 @@ pub enum PushProfileError {
     Sign(std::io::Error),
     #[error("Nix sign command resulted in a bad exit code: {0:?}")]
     SignExit(Option<i32>),
+    #[error("Failed to read signing key {0}: {1}")]
+    ReadSigningKey(PathBuf, std::io::Error),
+    #[error("Failed to run signing key command: {0}")]
+    SigningKeyCommand(std::io::Error),
+    #[error("Signing key command resulted in a bad exit code: {0:?}")]
+    SigningKeyCommandExit(Option<i32>),
+    #[error("Failed to run Nix key convert-secret-to-public command: {0}")]
+    PublicKey(std::io::Error),
+    #[error("Nix key convert-secret-to-public command resulted in a bad exit code: {0:?}")]
+    PublicKeyExit(Option<i32>),
+    #[error("Nix key convert-secret-to-public output contained an invalid UTF-8 sequence: {0}")]
+    PublicKeyUtf8(std::string::FromUtf8Error),
+    #[error("Failed to query the Nix configuration of the target: {0}")]
+    TrustedKeys(std::io::Error),
+    #[error("Querying the Nix configuration of the target resulted in a bad exit code: {0:?}")]
+    TrustedKeysExit(Option<i32>),
+    #[error("Failed to parse the Nix configuration of the target: {0}")]
+    TrustedKeysParse(serde_json::Error),
+    #[error(
+        "Node {0} does not trust any of the signing keys ({1}).\n\
+             Add one of them to `trusted-public-keys` on the node or deploy without --checksigs."
+    )]
+    UntrustedSigningKeys(String, String),
     #[error("Failed to run Nix copy command: {0}")]
     Copy(std::io::Error),
     #[error("Nix copy command resulted in a bad exit code: {0:?}")]
@@ -150,27 +176,236 @@ pub async fn build_profile_locally(
         return Err(PushProfileError::ActivateRsDoesntExist);
     }
 
-    if let Ok(local_key) = std::env::var("LOCAL_KEY") {
-        info!(
-            "Signing key present! Signing profile `{}` for node `{}`",
-            data.deploy_data.profile_name, data.deploy_data.node_name
-        );
+    sign_profile(data, None, "").await
+}
 
-        let sign_exit_status = Command::new("nix")
-            .arg("sign-paths")
-            .arg("-r")
-            .arg("-k")
-            .arg(local_key)
-            .arg(&data.deploy_data.profile.profile_settings.path)
-            .status()
+/// The keys to sign the profile with, falling back to the legacy `LOCAL_KEY` environment variable
+fn signing_keys(data: &PushProfileData) -> Vec<SigningKey> {
+    match &data.deploy_data.merged_settings.signing_keys {
+        Some(keys) => keys.clone(),
+        None => std::env::var("LOCAL_KEY")
+            .map(|key| vec![SigningKey::File(key.into())])
+            .unwrap_or_default(),
+    }
+}
+
+async fn read_signing_key(key: &SigningKey) -> Result<Vec<u8>, PushProfileError> {
+    match key {
+        SigningKey::File(path) => tokio::fs::read(path)
             .await
-            .map_err(PushProfileError::Sign)?;
+            .map_err(|e| PushProfileError::ReadSigningKey(path.clone(), e)),
+        SigningKey::Command { command } => {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stderr(Stdio::inherit())
+                .output()
+                .await
+                .map_err(PushProfileError::SigningKeyCommand)?;
+
+            match output.status.code() {
+                Some(0) => Ok(output.stdout),
+                a => Err(PushProfileError::SigningKeyCommandExit(a)),
+            }
+        }
+    }
+}
+
+async fn output_with_stdin(
+    command: &mut Command,
+    input: &[u8],
+) -> std::io::Result<std::process::Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child
+        .stdin
+        .take()
+        .expect("child did not have a stdin handle");
+    stdin.write_all(input).await?;
+    drop(stdin);
+
+    child.wait_with_output().await
+}
+
+/// Signs the profile closure with every signing key, in the given store or the local one
+async fn sign_profile(
+    data: &PushProfileData,
+    store: Option<&str>,
+    ssh_opts_str: &str,
+) -> Result<(), PushProfileError> {
+    let keys = signing_keys(data);
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    info!(
+        "Signing key present! Signing profile `{}` for node `{}`",
+        data.deploy_data.profile_name, data.deploy_data.node_name
+    );
+
+    for key in keys {
+        let mut sign_command = Command::new("nix");
+        sign_command
+            .arg("--experimental-features")
+            .arg("nix-command")
+            .arg("store")
+            .arg("sign")
+            .arg("--recursive");
+
+        if let Some(store) = store {
+            sign_command
+                .arg("--store")
+                .arg(store)
+                .env("NIX_SSHOPTS", ssh_opts_str);
+        }
+
+        sign_command.arg("--key-file");
+
+        let sign_exit_status = match key {
+            SigningKey::File(ref path) => sign_command
+                .arg(path)
+                .arg(&data.deploy_data.profile.profile_settings.path)
+                .status()
+                .await
+                .map_err(PushProfileError::Sign)?,
+            SigningKey::Command { .. } => {
+                let secret_key = read_signing_key(&key).await?;
+                sign_command
+                    .arg("/dev/stdin")
+                    .arg(&data.deploy_data.profile.profile_settings.path);
+
+                output_with_stdin(&mut sign_command, &secret_key)
+                    .await
+                    .map_err(PushProfileError::Sign)?
+                    .status
+            }
+        };
 
         match sign_exit_status.code() {
             Some(0) => (),
             a => return Err(PushProfileError::SignExit(a)),
         };
     }
+
+    Ok(())
+}
+
+async fn public_key(key: &SigningKey) -> Result<String, PushProfileError> {
+    let secret_key = read_signing_key(key).await?;
+
+    let output = output_with_stdin(
+        Command::new("nix")
+            .arg("--experimental-features")
+            .arg("nix-command")
+            .arg("key")
+            .arg("convert-secret-to-public"),
+        &secret_key,
+    )
+    .await
+    .map_err(PushProfileError::PublicKey)?;
+
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(PushProfileError::PublicKeyExit(a)),
+    };
+
+    Ok(String::from_utf8(output.stdout)
+        .map_err(PushProfileError::PublicKeyUtf8)?
+        .trim()
+        .to_string())
+}
+
+/// Extracts `trusted-public-keys` from the output of `nix show-config --json`
+fn parse_trusted_public_keys(config: &str) -> Result<Vec<String>, serde_json::Error> {
+    let config: serde_json::Value = serde_json::from_str(config)?;
+
+    let setting = match config.get("trusted-public-keys") {
+        Some(setting) => setting.get("value").unwrap_or(setting),
+        None => return Ok(Vec::new()),
+    };
+
+    // older Nix versions print the value as a single space separated string
+    Ok(match setting {
+        serde_json::Value::String(keys) => keys.split_whitespace().map(str::to_string).collect(),
+        serde_json::Value::Array(keys) => keys
+            .iter()
+            .filter_map(|key| key.as_str().map(str::to_string))
+            .collect(),
+        _ => Vec::new(),
+    })
+}
+
+#[test]
+fn test_parse_trusted_public_keys() {
+    assert_eq!(
+        parse_trusted_public_keys(
+            r#"{"trusted-public-keys": {"value": ["cache.nixos.org-1:6NCH", "deploy:abc="]}}"#
+        )
+        .unwrap(),
+        vec!["cache.nixos.org-1:6NCH", "deploy:abc="]
+    );
+    assert_eq!(
+        parse_trusted_public_keys(
+            r#"{"trusted-public-keys": "cache.nixos.org-1:6NCH deploy:abc="}"#
+        )
+        .unwrap(),
+        vec!["cache.nixos.org-1:6NCH", "deploy:abc="]
+    );
+    assert!(parse_trusted_public_keys("{}").unwrap().is_empty());
+}
+
+/// Makes sure that the target trusts at least one of the signing keys, so that a signed copy can succeed
+async fn check_trusted_keys(data: &PushProfileData) -> Result<(), PushProfileError> {
+    let mut public_keys = Vec::new();
+    for key in signing_keys(data) {
+        public_keys.push(public_key(&key).await?);
+    }
+
+    debug!(
+        "Checking that node `{}` trusts one of {:?}",
+        data.deploy_data.node_name, public_keys
+    );
+
+    let output = RetryPolicy::from_settings(&data.deploy_data.merged_settings)
+        .run(
+            "Querying the Nix configuration of the target",
+            |res: &std::io::Result<std::process::Output>| matches!(res, Ok(output) if is_ssh_connection_error(output.status.code())),
+            || {
+                let mut ssh_command = Command::new("ssh");
+                ssh_command.arg(data.deploy_data.ssh_addr(&data.deploy_defs.ssh_user));
+
+                for ssh_opt in &data.deploy_data.merged_settings.ssh_opts {
+                    ssh_command.arg(ssh_opt);
+                }
+
+                ssh_command
+                    .arg("nix --extra-experimental-features nix-command show-config --json")
+                    .stderr(Stdio::inherit());
+
+                async move { ssh_command.output().await }
+            },
+        )
+        .await
+        .map_err(PushProfileError::TrustedKeys)?;
+
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(PushProfileError::TrustedKeysExit(a)),
+    };
+
+    let trusted_keys = parse_trusted_public_keys(&String::from_utf8_lossy(&output.stdout))
+        .map_err(PushProfileError::TrustedKeysParse)?;
+
+    if !public_keys.iter().any(|key| trusted_keys.contains(key)) {
+        return Err(PushProfileError::UntrustedSigningKeys(
+            data.deploy_data.node_name.clone(),
+            public_keys.join(", "),
+        ));
+    }
+
     Ok(())
 }
 
@@ -202,12 +437,10 @@ pub async fn build_profile_remotely(
         data.deploy_data.profile_name, data.deploy_data.node_name
     );
 
-    // TODO: this should probably be handled more nicely during 'data' construction
-    let hostname = match data.deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &data.deploy_data.node.node_settings.hostname,
-    };
-    let store_address = format!("ssh-ng://{}@{}", data.deploy_defs.ssh_user, hostname);
+    let store_address = format!(
+        "ssh-ng://{}",
+        data.deploy_data.store_addr(&data.deploy_defs.ssh_user)
+    );
 
     let ssh_opts_str = shlex::try_join(
         data.deploy_data
@@ -220,34 +453,43 @@ pub async fn build_profile_remotely(
     .unwrap_or(data.deploy_data.merged_settings.ssh_opts.join(" "));
 
     // copy the derivation to remote host so it can be built there
-    let copy_command_status = {
-        let mut copy_command = Command::new("nix");
-        copy_command
-            .arg("--experimental-features")
-            .arg("nix-command");
-        copy_command
-            .arg("copy")
-            .arg("-s") // fetch dependencies from substitutors, not localhost
-            .arg("--to")
-            .arg(&store_address)
-            .arg("--derivation")
-            .arg(derivation_name)
-            .env("NIX_SSHOPTS", ssh_opts_str.clone());
-
-        debug!("copy command: {:?}", copy_command);
-
-        let mut child = copy_command
-            .stderr(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .expect("failed to spawn nix copy command");
-
-        if let Some(pb) = &data.deploy_data.progressbar {
-            update_pb_with_child_output(pb, &mut child).await;
-        }
-
-        child.wait().await.map_err(PushProfileError::Copy)?
-    };
+    let copy_command_status = RetryPolicy::from_settings(&data.deploy_data.merged_settings)
+        .run(
+            "Copying the derivation",
+            |res: &std::io::Result<std::process::ExitStatus>| matches!(res, Ok(status) if !status.success()),
+            || {
+                let mut copy_command = Command::new("nix");
+                copy_command
+                    .arg("--experimental-features")
+                    .arg("nix-command");
+                copy_command
+                    .arg("copy")
+                    .arg("-s") // fetch dependencies from substitutors, not localhost
+                    .arg("--to")
+                    .arg(&store_address)
+                    .arg("--derivation")
+                    .arg(derivation_name)
+                    .env("NIX_SSHOPTS", ssh_opts_str.clone());
+
+                debug!("copy command: {:?}", copy_command);
+
+                async move {
+                    let mut child = copy_command
+                        .stderr(Stdio::piped())
+                        .stdout(Stdio::piped())
+                        .spawn()
+                        .expect("failed to spawn nix copy command");
+
+                    if let Some(pb) = &data.deploy_data.progressbar {
+                        update_pb_with_child_output(pb, &mut child).await;
+                    }
+
+                    child.wait().await
+                }
+            },
+        )
+        .await
+        .map_err(PushProfileError::Copy)?;
 
     match copy_command_status.code() {
         Some(0) => (),
@@ -288,7 +530,7 @@ pub async fn build_profile_remotely(
         a => return Err(PushProfileError::BuildExit(a)),
     };
 
-    Ok(())
+    sign_profile(data, Some(&store_address), &ssh_opts_str).await
 }
 
 pub async fn build_profile(data: &PushProfileData) -> Result<(), PushProfileError> {
@@ -431,33 +673,42 @@ pub async fn push_profile(data: PushProfileData) -> Result<(), PushProfileError>
             data.deploy_data.profile_name, data.deploy_data.node_name
         );
 
-        let mut copy_command = Command::new("nix");
-        copy_command.arg("copy");
-
-        if data.deploy_data.merged_settings.fast_connection != Some(true) {
-            copy_command.arg("--substitute-on-destination");
-        }
-
-        if !data.check_sigs {
-            copy_command.arg("--no-check-sigs");
+        if data.check_sigs && !signing_keys(&data).is_empty() {
+            check_trusted_keys(&data).await?;
         }
 
-        let hostname = match data.deploy_data.cmd_overrides.hostname {
-            Some(ref x) => x,
-            None => &data.deploy_data.node.node_settings.hostname,
-        };
-
         let compress = data.deploy_data.merged_settings.compress.unwrap_or(false);
 
-        let copy_exit_status = copy_command
-            .arg("--to")
-            .arg(format!(
-                "ssh://{}@{}?compress={}",
-                data.deploy_defs.ssh_user, hostname, compress
-            ))
-            .arg(&data.deploy_data.profile.profile_settings.path)
-            .env("NIX_SSHOPTS", ssh_opts_str)
-            .status()
+        // copying is idempotent, so any failure is worth another attempt
+        let copy_exit_status = RetryPolicy::from_settings(&data.deploy_data.merged_settings)
+            .run(
+                "Copying the profile",
+                |res: &std::io::Result<std::process::ExitStatus>| matches!(res, Ok(status) if !status.success()),
+                || {
+                    let mut copy_command = Command::new("nix");
+                    copy_command.arg("copy");
+
+                    if data.deploy_data.merged_settings.fast_connection != Some(true) {
+                        copy_command.arg("--substitute-on-destination");
+                    }
+
+                    if !data.check_sigs {
+                        copy_command.arg("--no-check-sigs");
+                    }
+
+                    copy_command
+                        .arg("--to")
+                        .arg(format!(
+                            "ssh://{}?compress={}",
+                            data.deploy_data.store_addr(&data.deploy_defs.ssh_user),
+                            compress
+                        ))
+                        .arg(&data.deploy_data.profile.profile_settings.path)
+                        .env("NIX_SSHOPTS", &ssh_opts_str);
+
+                    async move { copy_command.status().await }
+                },
+            )
             .await
             .map_err(PushProfileError::Copy)?;
 
@@ -0,0 +1,128 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::warn;
+use std::future::Future;
+use std::time::Duration;
+
+use crate::data::GenericSettings;
+
+/// Exit code of ssh when the connection failed, any other code comes from the remote command
+pub const SSH_CONNECTION_ERROR: i32 = 255;
+
+/// Whether an ssh exit code means that the connection failed rather than the remote command
+pub fn is_ssh_connection_error(code: Option<i32>) -> bool {
+    code == Some(SSH_CONNECTION_ERROR)
+}
+
+/// How idempotent operations (copying, preflight checks, confirmation and status queries) are
+/// retried after a transient failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts made after the first one
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each further one
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_settings(settings: &GenericSettings) -> Self {
+        RetryPolicy {
+            retries: settings.retries.unwrap_or(2),
+            delay: Duration::from_secs(settings.retry_delay.unwrap_or(1).into()),
+        }
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        self.delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+    }
+
+    /// Runs `operation` until `should_retry` rejects its result or the retries run out
+    pub async fn run<T, Fut>(
+        &self,
+        what: &str,
+        should_retry: impl Fn(&T) -> bool,
+        mut operation: impl FnMut() -> Fut,
+    ) -> T
+    where
+        Fut: Future<Output = T>,
+    {
+        let mut retry = 0;
+        loop {
+            let result = operation().await;
+            if retry >= self.retries || !should_retry(&result) {
+                return result;
+            }
+
+            retry += 1;
+            let delay = self.backoff(retry);
+            warn!(
+                "{} failed, retrying in {}s ({}/{})",
+                what,
+                delay.as_secs_f32(),
+                retry,
+                self.retries
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_retry_policy() {
+    let policy = RetryPolicy {
+        retries: 3,
+        delay: Duration::from_secs(1),
+    };
+    assert_eq!(policy.backoff(1), Duration::from_secs(1));
+    assert_eq!(policy.backoff(3), Duration::from_secs(4));
+
+    let policy = RetryPolicy {
+        retries: 3,
+        delay: Duration::ZERO,
+    };
+
+    // retried until the connection error is gone
+    let mut attempts = 0;
+    let result = policy
+        .run(
+            "test",
+            |code| is_ssh_connection_error(*code),
+            || {
+                attempts += 1;
+                async move { if attempts < 3 { Some(255) } else { Some(0) } }
+            },
+        )
+        .await;
+    assert_eq!((result, attempts), (Some(0), 3));
+
+    // remote command failures are final
+    let mut attempts = 0;
+    let result = policy
+        .run(
+            "test",
+            |code| is_ssh_connection_error(*code),
+            || {
+                attempts += 1;
+                async { Some(1) }
+            },
+        )
+        .await;
+    assert_eq!((result, attempts), (Some(1), 1));
+
+    // gives up after the configured retries
+    let mut attempts = 0;
+    let result = policy
+        .run(
+            "test",
+            |code| is_ssh_connection_error(*code),
+            || {
+                attempts += 1;
+                async { Some(255) }
+            },
+        )
+        .await;
+    assert_eq!((result, attempts), (Some(255), 4));
+}
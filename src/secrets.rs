@@ -0,0 +1,351 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tokio::process::Command;
+
+use crate::data::Secret;
+
+/// A secret as sent from the deployer to the activator
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StagedSecret {
+    pub destination: PathBuf,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub mode: u32,
+    pub content: Vec<u8>,
+}
+
+/// A secret written by the activator, along with where the file it replaced was moved to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct InstalledSecret {
+    destination: PathBuf,
+    backup: Option<PathBuf>,
+}
+
+pub fn make_secrets_path(temp_path: &Path, closure: &str) -> PathBuf {
+    let hash = &closure["/nix/store/".len()..closure.find('-').unwrap_or(closure.len())];
+    temp_path.join(format!("deploy-rs-secrets-{}", hash))
+}
+
+/// Records the secrets installed with the current generation of a profile, so that they can be
+/// restored when the profile is rolled back
+fn make_manifest_path(profile_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.deploy-rs-secrets", profile_path))
+}
+
+fn make_sibling_path(destination: &Path, suffix: &str) -> PathBuf {
+    let file_name = destination
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    destination.with_file_name(format!(".{}.deploy-rs-{}", file_name, suffix))
+}
+
+#[derive(Error, Debug)]
+pub enum ReadSecretError {
+    #[error("Secret for {0} must have exactly one of `source` or `command` set")]
+    NoSource(PathBuf),
+    #[error("Invalid mode `{1}` for secret {0}, expected an octal number like \"0400\"")]
+    InvalidMode(PathBuf, String),
+    #[error("Failed to read secret file {0}: {1}")]
+    ReadFile(PathBuf, std::io::Error),
+    #[error("Failed to run secret command `{0}`: {1}")]
+    Command(String, std::io::Error),
+    #[error("Secret command `{0}` resulted in a bad exit code: {1:?}")]
+    CommandExit(String, Option<i32>),
+}
+
+/// Reads the content of a secret on the deploying machine
+pub async fn read_secret(secret: &Secret) -> Result<StagedSecret, ReadSecretError> {
+    let mode_str = secret.mode.as_deref().unwrap_or("0400");
+    let mode = u32::from_str_radix(mode_str.trim_start_matches("0o"), 8)
+        .map_err(|_| ReadSecretError::InvalidMode(secret.destination.clone(), mode_str.into()))?;
+
+    let content = match (&secret.source, &secret.command) {
+        (Some(source), None) => tokio::fs::read(source)
+            .await
+            .map_err(|e| ReadSecretError::ReadFile(source.clone(), e))?,
+        (None, Some(command)) => {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stderr(std::process::Stdio::inherit())
+                .output()
+                .await
+                .map_err(|e| ReadSecretError::Command(command.clone(), e))?;
+
+            match output.status.code() {
+                Some(0) => output.stdout,
+                a => return Err(ReadSecretError::CommandExit(command.clone(), a)),
+            }
+        }
+        _ => return Err(ReadSecretError::NoSource(secret.destination.clone())),
+    };
+
+    Ok(StagedSecret {
+        destination: secret.destination.clone(),
+        owner: secret.owner.clone(),
+        group: secret.group.clone(),
+        mode,
+        content,
+    })
+}
+
+#[derive(Error, Debug)]
+pub enum SecretsError {
+    #[error("Failed to write staged secrets to {0}: {1}")]
+    WriteStaged(PathBuf, std::io::Error),
+    #[error("Failed to read staged secrets from {0}: {1}")]
+    ReadStaged(PathBuf, std::io::Error),
+    #[error("Failed to decode staged secrets: {0}")]
+    Decode(#[from] serde_json::Error),
+    #[error("Failed to write secret {0}: {1}")]
+    Write(PathBuf, std::io::Error),
+    #[error("Failed to back up the existing file at {0}: {1}")]
+    Backup(PathBuf, std::io::Error),
+    #[error("Failed to restore secret {0}: {1}")]
+    Restore(PathBuf, std::io::Error),
+    #[error("Failed to run chown for secret {0}: {1}")]
+    Chown(PathBuf, std::io::Error),
+    #[error("chown for secret {0} resulted in a bad exit code: {1:?}")]
+    ChownExit(PathBuf, Option<i32>),
+    #[error("Failed to write secrets manifest {0}: {1}")]
+    WriteManifest(PathBuf, std::io::Error),
+    #[error("Failed to read secrets manifest {0}: {1}")]
+    ReadManifest(PathBuf, std::io::Error),
+}
+
+/// Stores secrets received from the deployer in a file only readable by the current user
+pub fn stage_secrets(staged_path: &Path, secrets: &[StagedSecret]) -> Result<(), SecretsError> {
+    let write = || -> std::io::Result<()> {
+        if let Some(parent) = staged_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Don't reuse a file someone else might have prepared for us
+        match std::fs::remove_file(staged_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(staged_path)?;
+        file.write_all(&serde_json::to_vec(secrets)?)?;
+        file.sync_all()
+    };
+
+    write().map_err(|e| SecretsError::WriteStaged(staged_path.to_path_buf(), e))
+}
+
+pub fn read_staged_secrets(staged_path: &Path) -> Result<Vec<StagedSecret>, SecretsError> {
+    let content = std::fs::read(staged_path)
+        .map_err(|e| SecretsError::ReadStaged(staged_path.to_path_buf(), e))?;
+    if let Err(e) = std::fs::remove_file(staged_path) {
+        warn!(
+            "Failed to remove staged secrets {}: {}",
+            staged_path.display(),
+            e
+        );
+    }
+    Ok(serde_json::from_slice(&content)?)
+}
+
+async fn chown(
+    path: &Path,
+    owner: &Option<String>,
+    group: &Option<String>,
+) -> Result<(), SecretsError> {
+    let spec = match (owner, group) {
+        (None, None) => return Ok(()),
+        (Some(owner), None) => owner.clone(),
+        (None, Some(group)) => format!(":{}", group),
+        (Some(owner), Some(group)) => format!("{}:{}", owner, group),
+    };
+
+    let status = Command::new("chown")
+        .arg(spec)
+        .arg(path)
+        .status()
+        .await
+        .map_err(|e| SecretsError::Chown(path.to_path_buf(), e))?;
+
+    match status.code() {
+        Some(0) => Ok(()),
+        a => Err(SecretsError::ChownExit(path.to_path_buf(), a)),
+    }
+}
+
+async fn install_secret(secret: &StagedSecret) -> Result<InstalledSecret, SecretsError> {
+    let destination = &secret.destination;
+    let tmp_path = make_sibling_path(destination, "tmp");
+
+    debug!("Writing secret to {}", tmp_path.display());
+
+    let write = || -> std::io::Result<()> {
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let _ = std::fs::remove_file(&tmp_path);
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(&secret.content)?;
+        file.sync_all()
+    };
+    write().map_err(|e| SecretsError::Write(destination.clone(), e))?;
+
+    chown(&tmp_path, &secret.owner, &secret.group).await?;
+    std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(secret.mode))
+        .map_err(|e| SecretsError::Write(destination.clone(), e))?;
+
+    // the destination stays in place until the new secret replaces it in a single rename
+    let backup = if destination.exists() {
+        let backup = make_sibling_path(destination, "backup");
+        let _ = std::fs::remove_file(&backup);
+        std::fs::hard_link(destination, &backup)
+            .or_else(|_| std::fs::copy(destination, &backup).map(|_| ()))
+            .map_err(|e| SecretsError::Backup(destination.clone(), e))?;
+        Some(backup)
+    } else {
+        None
+    };
+
+    std::fs::rename(&tmp_path, destination)
+        .map_err(|e| SecretsError::Write(destination.clone(), e))?;
+
+    info!("Installed secret {}", destination.display());
+
+    Ok(InstalledSecret {
+        destination: destination.clone(),
+        backup,
+    })
+}
+
+/// Installs the secrets, replacing each destination atomically. The replaced files are kept so
+/// that `restore_secrets` can put them back if the profile is rolled back.
+pub async fn install_secrets(
+    profile_path: &str,
+    secrets: &[StagedSecret],
+) -> Result<(), SecretsError> {
+    discard_backups(profile_path)?;
+
+    let manifest_path = make_manifest_path(profile_path);
+    let mut installed = Vec::new();
+
+    for secret in secrets {
+        let result = install_secret(secret).await;
+        if let Ok(ref x) = result {
+            installed.push(x.clone());
+        }
+
+        // Keep the manifest up to date after every secret, so a failure halfway through can still
+        // be rolled back
+        std::fs::write(&manifest_path, serde_json::to_vec(&installed)?)
+            .map_err(|e| SecretsError::WriteManifest(manifest_path.clone(), e))?;
+
+        result?;
+    }
+
+    Ok(())
+}
+
+fn read_manifest(profile_path: &str) -> Result<Option<Vec<InstalledSecret>>, SecretsError> {
+    let manifest_path = make_manifest_path(profile_path);
+    match std::fs::read(&manifest_path) {
+        Ok(x) => Ok(Some(serde_json::from_slice(&x)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(SecretsError::ReadManifest(manifest_path, e)),
+    }
+}
+
+/// Forgets about the files replaced by a previous deployment, which is no longer going to be
+/// rolled back
+pub fn discard_backups(profile_path: &str) -> Result<(), SecretsError> {
+    if let Some(installed) = read_manifest(profile_path)? {
+        for secret in installed {
+            if let Some(backup) = secret.backup {
+                let _ = std::fs::remove_file(backup);
+            }
+        }
+        let _ = std::fs::remove_file(make_manifest_path(profile_path));
+    }
+
+    Ok(())
+}
+
+/// Puts back the files replaced by the last deployment of a profile
+pub fn restore_secrets(profile_path: &str) -> Result<(), SecretsError> {
+    let installed = match read_manifest(profile_path)? {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+
+    for secret in installed.iter().rev() {
+        warn!(
+            "Restoring previous secret at {}",
+            secret.destination.display()
+        );
+        let result = match &secret.backup {
+            Some(backup) => std::fs::rename(backup, &secret.destination),
+            None => std::fs::remove_file(&secret.destination),
+        };
+        result.map_err(|e| SecretsError::Restore(secret.destination.clone(), e))?;
+    }
+
+    let _ = std::fs::remove_file(make_manifest_path(profile_path));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_install_and_restore_secrets() {
+    let dir = std::env::temp_dir().join(format!("deploy-rs-secrets-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let profile_path = dir.join("profile").display().to_string();
+    let existing = dir.join("existing.key");
+    let new = dir.join("sub/new.key");
+    std::fs::write(&existing, "old").unwrap();
+
+    let secret = |destination: &Path, content: &str| StagedSecret {
+        destination: destination.to_path_buf(),
+        owner: None,
+        group: None,
+        mode: 0o400,
+        content: content.as_bytes().to_vec(),
+    };
+
+    let staged_path = make_secrets_path(&dir, "/nix/store/abcd-profile");
+    stage_secrets(
+        &staged_path,
+        &[secret(&existing, "secret"), secret(&new, "other")],
+    )
+    .unwrap();
+    let staged = read_staged_secrets(&staged_path).unwrap();
+    assert!(!staged_path.exists());
+
+    install_secrets(&profile_path, &staged).await.unwrap();
+    assert_eq!(std::fs::read_to_string(&existing).unwrap(), "secret");
+    assert_eq!(std::fs::read_to_string(&new).unwrap(), "other");
+    assert_eq!(
+        std::fs::metadata(&new).unwrap().permissions().mode() & 0o777,
+        0o400
+    );
+
+    restore_secrets(&profile_path).unwrap();
+    assert_eq!(std::fs::read_to_string(&existing).unwrap(), "old");
+    assert!(!new.exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
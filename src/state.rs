@@ -0,0 +1,391 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Where the activation of a profile got to, in the order the states are reached
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ActivationState {
+    /// The profile points to the new generation, its activation script hasn't finished yet
+    SetProfile,
+    /// The activation script succeeded
+    Activated,
+    /// Set as the default boot entry, waiting for the node to boot into it and for the deployer to
+    /// confirm it there
+    AwaitingBoot,
+    /// Waiting for the deployer to confirm the activation
+    AwaitingConfirm,
+    /// The activation was confirmed, or didn't need confirmation
+    Confirmed,
+    /// The activation failed and the profile was left as it was
+    Failed,
+    /// The profile was rolled back to its previous generation
+    RolledBack,
+}
+
+impl std::fmt::Display for ActivationState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ActivationState::SetProfile => write!(f, "set-profile"),
+            ActivationState::Activated => write!(f, "activated"),
+            ActivationState::AwaitingBoot => write!(f, "awaiting-boot"),
+            ActivationState::AwaitingConfirm => write!(f, "awaiting-confirm"),
+            ActivationState::Confirmed => write!(f, "confirmed"),
+            ActivationState::Failed => write!(f, "failed"),
+            ActivationState::RolledBack => write!(f, "rolled-back"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Transition {
+    pub state: ActivationState,
+    /// Unix time the state was reached at
+    pub at: u64,
+}
+
+/// A generation of a profile, i.e. one of its `<profile>-<number>-link` symlinks
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Generation {
+    pub number: u64,
+    /// The store path the generation points to
+    pub path: String,
+}
+
+/// The number of the generation marked `(current)` in the output of `nix-env --list-generations`
+pub fn parse_current_generation(list: &str) -> Option<u64> {
+    list.lines()
+        .filter(|x| x.trim_end().ends_with("(current)"))
+        .find_map(|x| x.split_whitespace().next()?.parse().ok())
+}
+
+/// The highest generation number in the output of `nix-env --list-generations`
+pub fn parse_latest_generation(list: &str) -> Option<u64> {
+    list.lines()
+        .filter_map(|x| x.split_whitespace().next()?.parse().ok())
+        .max()
+}
+
+/// The state transitions of the latest activation of a profile
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct StateRecord {
+    pub closure: String,
+    /// `tempPath` of the activation, which holds its confirmation socket
+    pub temp_path: PathBuf,
+    pub run_id: Option<String>,
+    /// Seconds the boot-time check waits for confirmation, while it is armed
+    #[serde(default)]
+    pub boot_check: Option<u16>,
+    /// The generation the profile pointed to before the activation, which a rollback returns to
+    #[serde(default)]
+    pub previous_generation: Option<Generation>,
+    /// The generation the activation created, which a rollback deletes
+    #[serde(default)]
+    pub generation: Option<u64>,
+    /// Whether the activation managed generations natively, so that its rollback does the same.
+    /// Missing from records of older versions.
+    #[serde(default)]
+    pub native_profiles: Option<bool>,
+    pub transitions: Vec<Transition>,
+}
+
+impl StateRecord {
+    pub fn current(&self) -> Option<&Transition> {
+        self.transitions.last()
+    }
+}
+
+impl std::fmt::Display for StateRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.current() {
+            Some(current) => write!(
+                f,
+                "{} since {} ago",
+                current.state,
+                crate::lock::format_age(crate::lock::now().saturating_sub(current.at))
+            )?,
+            None => write!(f, "starting")?,
+        }
+        write!(f, ", {}", self.closure)?;
+        if let Some(run_id) = &self.run_id {
+            write!(f, " (run {})", run_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Next to the profile like its lock, so that it survives reboots unlike `tempPath`
+pub fn make_state_path(profile_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.deploy-rs-state", profile_path))
+}
+
+/// The confirmation token of an armed boot-time check, readable by the profile user only
+pub fn make_boot_token_path(profile_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.deploy-rs-boot-token", profile_path))
+}
+
+#[derive(Error, Debug)]
+pub enum StateError {
+    #[error("Failed to read the activation state {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to parse the activation state {0}: {1}")]
+    Parse(PathBuf, serde_json::Error),
+    #[error("Failed to write the activation state {0}: {1}")]
+    Write(PathBuf, std::io::Error),
+    #[error("Failed to access the boot check token {0}: {1}")]
+    BootToken(PathBuf, std::io::Error),
+}
+
+/// The state file of a profile, rewritten on every transition
+#[derive(Debug)]
+pub struct StateFile {
+    path: PathBuf,
+    record: StateRecord,
+}
+
+impl StateFile {
+    /// The state of a new activation of `closure`, only written once its first state is reached
+    pub fn new(
+        profile_path: &str,
+        closure: &str,
+        temp_path: &Path,
+        run_id: Option<String>,
+        native_profiles: bool,
+    ) -> Self {
+        StateFile {
+            path: make_state_path(profile_path),
+            record: StateRecord {
+                closure: closure.to_string(),
+                temp_path: temp_path.to_path_buf(),
+                run_id,
+                boot_check: None,
+                previous_generation: None,
+                generation: None,
+                native_profiles: Some(native_profiles),
+                transitions: Vec::new(),
+            },
+        }
+    }
+
+    /// The state of the latest activation of the profile, if it was ever recorded
+    pub fn open(profile_path: &str) -> Result<Option<Self>, StateError> {
+        let path = make_state_path(profile_path);
+
+        let contents = match std::fs::read(&path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(StateError::Read(path, e)),
+        };
+        let record =
+            serde_json::from_slice(&contents).map_err(|e| StateError::Parse(path.clone(), e))?;
+
+        Ok(Some(StateFile { path, record }))
+    }
+
+    pub fn record(&self) -> &StateRecord {
+        &self.record
+    }
+
+    /// Records the generation the profile points to before it is set, with the next transition
+    pub fn set_previous_generation(&mut self, previous_generation: Option<Generation>) {
+        self.record.previous_generation = previous_generation;
+    }
+
+    /// Records the generation setting the profile created, with the next transition
+    pub fn set_generation(&mut self, generation: Option<u64>) {
+        self.record.generation = generation;
+    }
+
+    fn boot_token_path(&self) -> PathBuf {
+        let state_path = self.path.display().to_string();
+        make_boot_token_path(
+            state_path
+                .strip_suffix(".deploy-rs-state")
+                .unwrap_or(&state_path),
+        )
+    }
+
+    /// Arms the boot-time check: after a reboot, `activate-rs recover` waits `confirm_timeout` for
+    /// the deployer to confirm the activation with `token`. Recorded with the next transition.
+    pub fn arm_boot_check(&mut self, token: &str, confirm_timeout: u16) -> Result<(), StateError> {
+        let path = self.boot_token_path();
+        let write = || -> std::io::Result<()> {
+            // recreated rather than truncated, so that it can't keep the mode of a leftover
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)?;
+            file.write_all(token.as_bytes())?;
+            file.sync_all()
+        };
+        write().map_err(|e| StateError::BootToken(path.clone(), e))?;
+
+        self.record.boot_check = Some(confirm_timeout);
+        Ok(())
+    }
+
+    /// The token of the armed boot-time check, if it is still there
+    pub fn boot_token(&self) -> Result<Option<String>, StateError> {
+        let path = self.boot_token_path();
+        match std::fs::read_to_string(&path) {
+            Ok(x) => Ok(Some(x.trim().to_string())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(StateError::BootToken(path, e)),
+        }
+    }
+
+    /// Disarms the boot-time check, recorded with the next transition
+    pub fn disarm_boot_check(&mut self) -> Result<(), StateError> {
+        if self.record.boot_check.take().is_none() {
+            return Ok(());
+        }
+
+        let path = self.boot_token_path();
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(StateError::BootToken(path, e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Records that the activation reached `state`. The file is replaced as a whole and synced, so
+    /// that it is never found half-written, not even after a crash.
+    pub fn transition(&mut self, state: ActivationState) -> Result<(), StateError> {
+        self.record.transitions.push(Transition {
+            state,
+            at: crate::lock::now(),
+        });
+
+        let staging = PathBuf::from(format!("{}.tmp", self.path.display()));
+        let write = || -> std::io::Result<()> {
+            let mut file = std::fs::File::create(&staging)?;
+            file.write_all(&serde_json::to_vec(&self.record)?)?;
+            file.sync_all()?;
+            std::fs::rename(&staging, &self.path)
+        };
+
+        write().map_err(|e| StateError::Write(self.path.clone(), e))
+    }
+}
+
+/// Profiles with a recorded activation state in the usual profile directories, i.e. the system
+/// profile and those in `per-user`
+pub fn find_recorded_profiles(nix_state_dir: &Path) -> std::io::Result<Vec<String>> {
+    let profiles_dir = nix_state_dir.join("profiles");
+    let mut dirs = vec![profiles_dir.clone()];
+    match std::fs::read_dir(profiles_dir.join("per-user")) {
+        Ok(users) => {
+            for user in users {
+                dirs.push(user?.path());
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+
+    let mut profiles = Vec::new();
+    for dir in dirs {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let path = entry?.path().display().to_string();
+            if let Some(profile_path) = path.strip_suffix(".deploy-rs-state") {
+                profiles.push(profile_path.to_string());
+            }
+        }
+    }
+    profiles.sort();
+
+    Ok(profiles)
+}
+
+#[test]
+fn test_state_file() {
+    let dir = std::env::temp_dir().join(format!("deploy-rs-state-test-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("profiles/per-user/alice")).unwrap();
+    let system = dir.join("profiles/system").display().to_string();
+    let home = dir
+        .join("profiles/per-user/alice/home")
+        .display()
+        .to_string();
+
+    assert!(StateFile::open(&system).unwrap().is_none());
+
+    let mut state = StateFile::new(
+        &system,
+        "/nix/store/abc123-system",
+        Path::new("/tmp"),
+        Some("1-2".to_string()),
+        true,
+    );
+    state.set_previous_generation(Some(Generation {
+        number: 41,
+        path: "/nix/store/xyz789-system".to_string(),
+    }));
+    state.transition(ActivationState::SetProfile).unwrap();
+    state.set_generation(Some(43));
+    state.transition(ActivationState::AwaitingConfirm).unwrap();
+    StateFile::new(
+        &home,
+        "/nix/store/def456-home",
+        Path::new("/tmp"),
+        None,
+        false,
+    )
+    .transition(ActivationState::Confirmed)
+    .unwrap();
+
+    state.arm_boot_check("token", 60).unwrap();
+    state.transition(ActivationState::AwaitingBoot).unwrap();
+    let reopened = StateFile::open(&system).unwrap().unwrap();
+    assert_eq!(reopened.record().boot_check, Some(60));
+    assert_eq!(reopened.record().native_profiles, Some(true));
+    assert_eq!(reopened.boot_token().unwrap().as_deref(), Some("token"));
+    state.disarm_boot_check().unwrap();
+    state.transition(ActivationState::AwaitingConfirm).unwrap();
+    assert_eq!(state.boot_token().unwrap(), None);
+
+    let reopened = StateFile::open(&system).unwrap().unwrap();
+    assert_eq!(reopened.record(), state.record());
+    assert_eq!(
+        reopened.record().current().map(|x| x.state),
+        Some(ActivationState::AwaitingConfirm)
+    );
+    assert_eq!(
+        serde_json::to_value(ActivationState::AwaitingConfirm).unwrap(),
+        ActivationState::AwaitingConfirm.to_string()
+    );
+
+    assert_eq!(find_recorded_profiles(&dir).unwrap(), vec![home, system]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_parse_current_generation() {
+    let list = "  41   2024-03-01 10:00:00   \n  42   2024-03-02 11:30:00   (current)\n  43   2024-03-03 09:15:00   \n";
+    assert_eq!(parse_current_generation(list), Some(42));
+    assert_eq!(
+        parse_current_generation("  41   2024-03-01 10:00:00   \n"),
+        None
+    );
+    assert_eq!(parse_current_generation(""), None);
+    assert_eq!(parse_latest_generation(list), Some(43));
+    assert_eq!(parse_latest_generation(""), None);
+}